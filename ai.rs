@@ -0,0 +1,381 @@
+use map;
+use map::{Action, Creature, Direction, MoveController, MapView, Position, DIRECTIONS};
+use dijkstra;
+use dijkstra::DijkstraMap;
+use rng::GameRng;
+use species;
+
+/**
+ * What a monster is up to
+ */
+pub enum State {
+	// Nothing in sight: roam around
+	WANDER,
+	// Chase an enemy in sight, last seen at the position
+	HUNT(@mut Creature, Position),
+	// Lost sight of the enemy: go look where it was seen last, for
+	// at most the given number of decisions
+	SEARCH(Position, uint),
+	// Run away from the enemy for at most the given number of
+	// decisions, counted from the last one it was in sight for
+	FLEE(@mut Creature, uint)
+}
+
+/**
+ * Monster behaviour as a state machine driven by what the monster sees.
+ * Doesn't touch the UI, so it can run headless.
+ */
+pub struct Mind {
+	state : State,
+	// Most decisions spent searching, or fleeing since the enemy was last seen
+	patience : uint,
+	// Generator of its own, None to draw from the map's
+	rng : Option<@mut GameRng>
+}
+
+pub impl Mind {
+	fn new() -> Mind {
+		Mind { state: WANDER, patience: 10, rng: None }
+	}
+
+	// Move to the next state according to what `cr` sees now
+	fn update(&mut self, cr : @mut Creature) {
+		let enemy = nearest_enemy(cr);
+
+		self.state = match (self.state, enemy) {
+			(_, Some(e)) if cr.life * 100 <= cr.stats.max_hp * cr.behaviour.flee_percent() =>
+				FLEE(e, self.patience),
+			(_, Some(e)) => HUNT(e, e.pos),
+			(FLEE(e, calm), None) if calm > 1 => FLEE(e, calm - 1),
+			(HUNT(target, _), None) if !target.alive() => WANDER,
+			(HUNT(_, last_seen), None) => SEARCH(last_seen, self.patience),
+			(SEARCH(pos, n), None) if n > 1 && pos != cr.pos => SEARCH(pos, n - 1),
+			_ => WANDER
+		};
+	}
+
+	fn decide(&mut self, cr : @mut Creature) -> Action {
+		self.update(cr);
+
+		let rng = match self.rng {
+			Some(rng) => rng,
+			None => cr.map.rng
+		};
+		match self.state {
+			WANDER if cr.behaviour == species::SENTRY => keep_watch(rng),
+			WANDER => wander(cr, rng),
+			HUNT(target, _) => {
+				match adjacent_dir(cr, target) {
+					Some(d) if d == cr.dir => map::MELEE(map::FORWARD),
+					Some(d) => cr.action_towards(d),
+					None => match shot_at(cr, target) {
+						Some(action) => action,
+						None => roll(cr, dijkstra::flow_to_creature(cr.map, target), rng)
+					}
+				}
+			},
+			SEARCH(pos, _) => roll(cr, dijkstra::flow_to(cr.map, &pos), rng),
+			FLEE(enemy, _) => flee(cr, enemy)
+		}
+	}
+}
+
+// Nearest creature hostile to `cr` that it can see
+pub fn nearest_enemy(cr : @mut Creature) -> Option<@mut Creature> {
+	let mut nearest = None;
+	let mut nearest_dist = int::max_value;
+
+	for cr.visible_creatures().each |&other| {
+		if cr.is_hostile(other) && other.alive() {
+			let dist = cr.map.distance(&cr.pos, &other.pos);
+			if dist < nearest_dist {
+				nearest = Some(other);
+				nearest_dist = dist;
+			}
+		}
+	}
+	nearest
+}
+
+// Direction from `cr` to `other` if they are neighbours
+fn adjacent_dir(cr : @mut Creature, other : @mut Creature) -> Option<Direction> {
+	for DIRECTIONS.each |&d| {
+		let n = cr.pos.neighbor(d);
+		if cr.map.normalize(&n) == Some(other.pos) {
+			return Some(d);
+		}
+	}
+	None
+}
+
+// Shot that would hit `target`, if `cr` can shoot and nobody is in the way
+fn shot_at(cr : @mut Creature, target : @mut Creature) -> Option<Action> {
+	if cr.stats.range <= 0 {
+		return None;
+	}
+	let offset = cr.map.offset(&cr.pos, &target.pos);
+	match cr.line_of_fire(&offset) {
+		Some(other) if other.id == target.id => Some(map::FIRE(offset)),
+		_ => None
+	}
+}
+
+// Roll down a distance map
+fn roll(cr : @mut Creature, flow : @DijkstraMap, rng : @mut GameRng) -> Action {
+	let map = cr.map;
+	let pos = cr.pos;
+
+	match flow.downhill(map, &pos) {
+		Some(d) => cr.action_towards(d),
+		None => wander(cr, rng)
+	}
+}
+
+fn flee(cr : @mut Creature, enemy : @mut Creature) -> Action {
+	let map = cr.map;
+	let pos = cr.pos;
+
+	match dijkstra::flow_from_creature(map, enemy).downhill(map, &pos) {
+		Some(d) => cr.action_towards(d),
+		None => {
+			// Cornered
+			match adjacent_dir(cr, enemy) {
+				Some(d) if d == cr.dir => map::MELEE(map::FORWARD),
+				Some(d) => cr.action_towards(d),
+				None => map::WAIT
+			}
+		}
+	}
+}
+
+// Stay put, looking around now and then
+fn keep_watch(rng : @mut GameRng) -> Action {
+	match rng.gen_int_range(0, 40) {
+		0 => map::TURN(map::LEFT),
+		1 => map::TURN(map::RIGHT),
+		_ => map::WAIT
+	}
+}
+
+// Walk around at random, hitting enemies that get in the way
+fn wander(cr : @mut Creature, rng : @mut GameRng) -> Action {
+	for [map::FORWARD, map::LEFT, map::RIGHT].each |&rd| {
+		let pos = cr.pos.neighbor(cr.dir.turn(rd));
+		match cr.map.creature_at(&pos) {
+			Some(other) if cr.is_hostile(other) => return map::MELEE(rd),
+			_ => {}
+		}
+	}
+
+	match rng.gen_int_range(0, 10) {
+		0 => map::TURN(map::LEFT),
+		1 => map::TURN(map::RIGHT),
+		_ => {
+			let front = cr.pos.neighbor(cr.dir);
+			if cr.map.at(&front).is_passable() {
+				map::MOVE(map::FORWARD)
+			} else {
+				map::TURN(map::LEFT)
+			}
+		}
+	}
+}
+
+pub struct MonsterController {
+	mind : @mut Mind
+}
+
+pub impl MonsterController {
+	fn new() -> MonsterController {
+		MonsterController { mind: @mut Mind::new() }
+	}
+
+	// Controller whose random choices don't disturb the map's generator
+	fn with_rng(rng : @mut GameRng) -> MonsterController {
+		MonsterController { mind: @mut Mind { rng: Some(rng), .. Mind::new() } }
+	}
+}
+
+impl MoveController for MonsterController {
+	fn get_move(&mut self, cr : @mut Creature) -> Action {
+		self.mind.decide(cr)
+	}
+
+	fn type_tag(&self) -> &'static str {
+		"monster"
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use core::vec;
+
+	use ai::{Mind, MonsterController, State, WANDER, HUNT, SEARCH, FLEE};
+	use dungeon;
+	use dungeon::Dungeon;
+	use engine::{Engine, NullRenderer, Renderer};
+	use map;
+	use map::{Creature, Map, Position};
+	use rng::GameRng;
+
+	/*
+	 * A lit 12x12 room with a wall across the middle:
+	 *
+	 * the monster stands at 5,8 facing north and the hero at 5,6, in
+	 * plain sight; from 5,4, behind the wall, the hero can't be seen.
+	 */
+	fn setup() -> (Engine, @mut Creature, @mut Creature) {
+		let mut tiles = vec::from_fn(12, |_| vec::from_elem(12, map::FLOOR));
+		for [4, 5, 6].each |&x| {
+			tiles[x][5] = map::WALL;
+		}
+		let level = @mut Map::from_tiles(tiles, map::BOUNDED, @mut GameRng::new(1));
+		let renderer = @mut NullRenderer::new() as @mut Renderer;
+		let mut engine = Engine::new(Dungeon::new(~[level], dungeon::FREEZE), renderer);
+
+		let monster = level.spawn_creature(&Position {x: 5, y: 8}, map::N,
+			@MonsterController::new()).get();
+		let hero = level.spawn_creature(&Position {x: 5, y: 6}, map::S,
+			@MonsterController::new()).get();
+		hero.faction = map::HEROES;
+		engine.add_creature(monster);
+		engine.add_creature(hero);
+		(engine, monster, hero)
+	}
+
+	// Move `cr` and let `watcher` look again
+	fn move_to(cr : @mut Creature, pos : Position, watcher : @mut Creature) {
+		cr.map.move_creature(cr, &pos);
+		watcher.update_visibility();
+	}
+
+	fn name(state : &State) -> &'static str {
+		match *state {
+			WANDER => "wander",
+			HUNT(*) => "hunt",
+			SEARCH(*) => "search",
+			FLEE(*) => "flee"
+		}
+	}
+
+	#[test]
+	fn test_hunt_visible_enemy() {
+		let (_engine, monster, hero) = setup();
+		let mut mind = Mind::new();
+
+		move_to(hero, Position {x: 5, y: 4}, monster);
+		mind.update(monster);
+		assert!(name(&mind.state) == "wander");
+
+		move_to(hero, Position {x: 5, y: 6}, monster);
+		mind.update(monster);
+		match mind.state {
+			HUNT(target, pos) => {
+				assert!(target.id == hero.id);
+				assert!(pos == Position {x: 5, y: 6});
+			},
+			_ => fail!(~"not hunting")
+		}
+	}
+
+	#[test]
+	fn test_search_where_last_seen() {
+		let (_engine, monster, hero) = setup();
+		let mut mind = Mind::new();
+
+		mind.update(monster);
+		move_to(hero, Position {x: 5, y: 4}, monster);
+		mind.update(monster);
+		match mind.state {
+			SEARCH(pos, n) => {
+				assert!(pos == Position {x: 5, y: 6});
+				assert!(n == mind.patience);
+			},
+			_ => fail!(~"not searching")
+		}
+	}
+
+	#[test]
+	fn test_search_runs_out_of_patience() {
+		let (_engine, monster, hero) = setup();
+		let mut mind = Mind::new();
+		mind.patience = 3;
+
+		mind.update(monster);
+		move_to(hero, Position {x: 5, y: 4}, monster);
+		for uint::range(0, 3) |_| {
+			mind.update(monster);
+			assert!(name(&mind.state) == "search");
+		}
+		mind.update(monster);
+		assert!(name(&mind.state) == "wander");
+	}
+
+	#[test]
+	fn test_search_reaches_position() {
+		let (_engine, monster, hero) = setup();
+		let mut mind = Mind::new();
+
+		mind.update(monster);
+		move_to(hero, Position {x: 5, y: 4}, monster);
+		mind.update(monster);
+		assert!(name(&mind.state) == "search");
+
+		move_to(monster, Position {x: 5, y: 6}, monster);
+		mind.update(monster);
+		assert!(name(&mind.state) == "wander");
+	}
+
+	#[test]
+	fn test_flee_when_hurt() {
+		let (_engine, monster, hero) = setup();
+		let mut mind = Mind::new();
+
+		// Just above the threshold
+		monster.life = monster.stats.max_hp * monster.behaviour.flee_percent() / 100 + 1;
+		mind.update(monster);
+		assert!(name(&mind.state) == "hunt");
+
+		monster.life = monster.stats.max_hp * monster.behaviour.flee_percent() / 100;
+		mind.update(monster);
+		match mind.state {
+			FLEE(enemy, calm) => {
+				assert!(enemy.id == hero.id);
+				assert!(calm == mind.patience);
+			},
+			_ => fail!(~"not fleeing")
+		}
+	}
+
+	#[test]
+	fn test_flee_runs_out_of_patience() {
+		let (_engine, monster, hero) = setup();
+		let mut mind = Mind::new();
+		mind.patience = 3;
+
+		monster.life = 1;
+		mind.update(monster);
+		move_to(hero, Position {x: 5, y: 4}, monster);
+		for uint::range(0, 2) |_| {
+			mind.update(monster);
+			assert!(name(&mind.state) == "flee");
+		}
+		mind.update(monster);
+		assert!(name(&mind.state) == "wander");
+	}
+
+	#[test]
+	fn test_wander_when_target_dies() {
+		let (_engine, monster, hero) = setup();
+		let mut mind = Mind::new();
+
+		mind.update(monster);
+		assert!(name(&mind.state) == "hunt");
+
+		let life = hero.life;
+		hero.take_damage(life);
+		monster.update_visibility();
+		mind.update(monster);
+		assert!(name(&mind.state) == "wander");
+	}
+}
@@ -0,0 +1,188 @@
+use map::{Creature, Direction, HEROES};
+use rng::GameRng;
+
+/**
+ * Fighting abilities of a creature
+ */
+pub struct Stats {
+	max_hp : int,
+	// Highest damage of a hit; a hit does between half of it and all of it
+	attack : int,
+	// Damage taken off every hit, except criticals
+	defence : int,
+	// Added to the chance to hit, in percent
+	accuracy : int,
+	// Taken off the attacker's chance to hit, in percent
+	evasion : int,
+	// Hexes a shot reaches, 0 for creatures that can't shoot
+	range : int
+}
+
+pub static DEFAULT_STATS : Stats = Stats { max_hp: 8, attack: 3, defence: 0, accuracy: 0, evasion: 0, range: 0 };
+pub static PLAYER_STATS : Stats = Stats { max_hp: 20, attack: 5, defence: 1, accuracy: 10, evasion: 10, range: 6 };
+
+// Chance to hit between equally skilled creatures, in percent
+static BASE_HIT_PERCENT : int = 75;
+static MIN_HIT_PERCENT : int = 5;
+static MAX_HIT_PERCENT : int = 95;
+static CRITICAL_PERCENT : int = 5;
+
+// Chance of a frontal attack being blocked without a shield, in percent
+static BLOCK_PERCENT : int = 15;
+// Bonus to hit when attacking from the side
+static FLANK_ACCURACY : int = 15;
+// Bonus to hit and damage multiplier when attacking from behind
+static BACKSTAB_ACCURACY : int = 30;
+static BACKSTAB_PERCENT : int = 200;
+// Taken off the chance to hit for every hex a shot flies beyond the first
+static RANGE_PENALTY : int = 5;
+
+/**
+ * Which side of the defender an attack comes from, relative to where
+ * the defender faces
+ */
+#[deriving(Eq)]
+pub enum Side {
+	FRONT,
+	FLANK,
+	BEHIND
+}
+
+pub impl Side {
+	// Side of a defender facing `defender_dir` hit by an attack going in `attack_dir`
+	fn of(attack_dir : Direction, defender_dir : Direction) -> Side {
+		// Where the attacker stands, seen from the defender
+		let from = attack_dir.turn_by_int(3).relative_to(defender_dir);
+		match from.to_uint() {
+			0 => FRONT,
+			3 => BEHIND,
+			_ => FLANK
+		}
+	}
+
+	fn accuracy_bonus(&self) -> int {
+		match *self {
+			FRONT => 0,
+			FLANK => FLANK_ACCURACY,
+			BEHIND => BACKSTAB_ACCURACY
+		}
+	}
+
+	fn damage_percent(&self) -> int {
+		match *self {
+			BEHIND => BACKSTAB_PERCENT,
+			_ => 100
+		}
+	}
+}
+
+pub enum Outcome {
+	MISS,
+	// Stopped by the defender, only possible from the front
+	BLOCKED,
+	HIT(int),
+	// Double damage that ignores defence
+	CRITICAL(int)
+}
+
+/**
+ * What happened in one attack
+ */
+pub struct CombatResult {
+	attacker : @mut Creature,
+	defender : @mut Creature,
+	side : Side,
+	outcome : Outcome,
+	killed : bool
+}
+
+pub impl CombatResult {
+	fn damage(&self) -> int {
+		match self.outcome {
+			MISS | BLOCKED => 0,
+			HIT(d) | CRITICAL(d) => d
+		}
+	}
+}
+
+// Chance of `attacker` hitting `defender`, in percent
+pub fn hit_chance(attacker : &Stats, defender : &Stats) -> int {
+	let chance = BASE_HIT_PERCENT + attacker.accuracy - defender.evasion;
+	int::max(MIN_HIT_PERCENT, int::min(MAX_HIT_PERCENT, chance))
+}
+
+// Accuracy modifier of a shot at a creature `distance` hexes away
+pub fn range_penalty(distance : int) -> int {
+	-RANGE_PENALTY * int::max(0, distance - 1)
+}
+
+/**
+ * Roll an attack of `attacker` on `defender` from `side` and apply the
+ * damage. `accuracy` is added to the chance to hit.
+ */
+pub fn resolve(attacker : @mut Creature, defender : @mut Creature, side : Side,
+		accuracy : int, rng : @mut GameRng) -> CombatResult {
+	let (att, def) = (attacker.effective_stats(), defender.effective_stats());
+	let chance = hit_chance(&att, &def) + side.accuracy_bonus() + accuracy;
+	let chance = int::max(MIN_HIT_PERCENT, int::min(MAX_HIT_PERCENT, chance));
+	let outcome = if rng.gen_int_range(0, 100) >= chance {
+		MISS
+	} else if side == FRONT && rng.gen_int_range(0, 100) < BLOCK_PERCENT + defender.block_bonus() {
+		BLOCKED
+	} else {
+		let max = int::max(1, att.attack);
+		let damage = rng.gen_int_range((max + 1) / 2, max + 1) * side.damage_percent() / 100;
+		if rng.gen_int_range(0, 100) < CRITICAL_PERCENT {
+			CRITICAL(2 * damage)
+		} else {
+			HIT(int::max(0, damage - def.defence))
+		}
+	};
+
+	let result = CombatResult {
+		attacker: attacker, defender: defender, side: side, outcome: outcome, killed: false
+	};
+	if result.damage() > 0 {
+		defender.take_damage(result.damage());
+	}
+	CombatResult { killed: !defender.alive(), .. result }
+}
+
+// How the creature is called in messages
+pub fn name(cr : @mut Creature) -> ~str {
+	match cr.faction {
+		HEROES => ~"you",
+		_ => ~"the " + cr.name
+	}
+}
+
+// Message for the log
+pub fn describe(result : &CombatResult) -> ~str {
+	let attacker = name(result.attacker);
+	let defender = name(result.defender);
+	let you = result.attacker.faction == HEROES;
+	let defender = match result.side {
+		FRONT => defender,
+		FLANK => defender + " from the side",
+		BEHIND => defender + " from behind"
+	};
+
+	let text = match result.outcome {
+		MISS => fmt!("%s %s %s", attacker, if you { "miss" } else { "misses" }, defender),
+		BLOCKED if you => fmt!("%s blocks your blow", name(result.defender)),
+		BLOCKED => fmt!("%s %s the blow of %s", name(result.defender),
+			if result.defender.faction == HEROES { "block" } else { "blocks" }, attacker),
+		HIT(0) => fmt!("%s %s %s, doing no harm", attacker,
+			if you { "hit" } else { "hits" }, defender),
+		HIT(d) => fmt!("%s %s %s for %d", attacker, if you { "hit" } else { "hits" }, defender, d),
+		CRITICAL(d) => fmt!("%s critically %s %s for %d", attacker,
+			if you { "hit" } else { "hits" }, defender, d)
+	};
+
+	if result.killed {
+		text + fmt!(", %s %s", name(result.defender),
+			if result.defender.faction == HEROES { "die" } else { "dies" })
+	} else {
+		text
+	}
+}
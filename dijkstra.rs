@@ -0,0 +1,215 @@
+use core::vec;
+use std::priority_queue::PriorityQueue;
+
+use map::{Creature, Direction, Map, MapView, Position, DIRECTIONS};
+use path::Lowest;
+
+/*
+ * Dijkstra maps
+ *
+ * A distance field over the whole map: every passable tile holds the
+ * number of steps to the nearest goal. Creatures approach a goal by
+ * rolling downhill and flee by rolling downhill on the inverted field,
+ * which is the distance field multiplied by a negative factor and
+ * scanned again, so that dead ends are no longer the best places to
+ * run to.
+ *
+ * One field serves every creature heading for the same goal, so maps
+ * are cached on the `Map`. Maps towards a creature are kept one per
+ * creature, computed again from scratch whenever it has moved and
+ * dropped once it dies or leaves the level, so any number of monsters
+ * chasing the player share a single map; maps towards other places are
+ * kept for the last few places asked for.
+ */
+
+pub static UNREACHED : int = int::max_value;
+
+// Percentage by which distances are multiplied (and negated) for flee maps
+static FLEE_PERCENT : int = 120;
+
+// Maps towards places, not creatures, kept on a map
+static FLOW_CACHE_SIZE : uint = 8;
+
+pub struct DijkstraMap {
+	values : ~[~[int]],
+	goals : ~[Position],
+	// Made for fleeing from the goals
+	flee : bool,
+	// Id of the creature standing on the goal, for maps that follow it
+	target : Option<uint>
+}
+
+pub impl DijkstraMap {
+	// Distances to the nearest of `goals`
+	fn new(map : &Map, goals : &[Position]) -> DijkstraMap {
+		DijkstraMap::new_where(map, goals, |p| map.tiles[p.x][p.y].is_passable())
+	}
+
+	// Distances to the nearest of `goals` through the tiles `passable` allows
+	fn new_where(map : &Map, goals : &[Position], passable : &fn(&Position) -> bool) -> DijkstraMap {
+		let mut dm = DijkstraMap {
+			values: vec::from_elem(map.width, vec::from_elem(map.height, UNREACHED)),
+			goals: ~[],
+			flee: false,
+			target: None
+		};
+		dm.set_goals(map, goals);
+		dm.rescan_where(map, passable);
+		dm
+	}
+
+	// Start over from new goals. Takes a `rescan` to fill in the distances.
+	fn set_goals(&mut self, map : &Map, goals : &[Position]) {
+		for uint::range(0, map.width) |x| {
+			for uint::range(0, map.height) |y| {
+				self.values[x][y] = UNREACHED;
+			}
+		}
+		self.goals = ~[];
+		for goals.each |goal| {
+			match map.normalize(goal) {
+				Some(p) => {
+					self.values[p.x][p.y] = 0;
+					self.goals.push(p);
+				},
+				None => {}
+			}
+		}
+	}
+
+	/**
+	 * Lower every tile to one more than its lowest neighbour, until
+	 * nothing changes.
+	 */
+	fn rescan(&mut self, map : &Map) {
+		self.rescan_where(map, |p| map.tiles[p.x][p.y].is_passable());
+	}
+
+	// `rescan` through the tiles `passable` allows
+	fn rescan_where(&mut self, map : &Map, passable : &fn(&Position) -> bool) {
+		let mut open = PriorityQueue::new();
+
+		for uint::range(0, map.width) |x| {
+			for uint::range(0, map.height) |y| {
+				if self.values[x][y] != UNREACHED {
+					open.push(Lowest {
+						priority: self.values[x][y],
+						item: Position {x: x as int, y: y as int}
+					});
+				}
+			}
+		}
+
+		while !open.is_empty() {
+			let e = open.pop();
+			let (pos, value) = (e.item, e.priority);
+			if value > self.values[pos.x][pos.y] {
+				loop;
+			}
+
+			for DIRECTIONS.each |&d| {
+				let n = match map.normalize(&pos.neighbor(d)) {
+					Some(n) => n,
+					None => loop
+				};
+				if passable(&n) && value + 1 < self.values[n.x][n.y] {
+					self.values[n.x][n.y] = value + 1;
+					open.push(Lowest { priority: value + 1, item: n });
+				}
+			}
+		}
+	}
+
+	/**
+	 * Map for running away from the goals: distances multiplied by
+	 * -`percent` / 100, then rescanned.
+	 */
+	fn inverted(&self, map : &Map, percent : int) -> DijkstraMap {
+		let values = do self.values.map |column| {
+			do column.map |&v| {
+				if v == UNREACHED { v } else { -v * percent / 100 }
+			}
+		};
+		let mut dm = DijkstraMap { values: values, goals: copy self.goals, flee: true, target: self.target };
+		dm.rescan(map);
+		dm
+	}
+
+	fn value(&self, map : &Map, pos : &Position) -> int {
+		match map.normalize(pos) {
+			Some(p) => self.values[p.x][p.y],
+			None => UNREACHED
+		}
+	}
+
+	// Direction of the free neighbour of `pos` with the lowest value, if lower than at `pos`
+	fn downhill(&self, map : &mut Map, pos : &Position) -> Option<Direction> {
+		let mut best = None;
+		let mut best_value = self.value(map, pos);
+
+		for DIRECTIONS.each |&d| {
+			let n = pos.neighbor(d);
+			let v = self.value(map, &n);
+			if v < best_value && map.creature_at(&n).is_none() {
+				best = Some(d);
+				best_value = v;
+			}
+		}
+		best
+	}
+}
+
+// Map of `map` towards `goal`, or away from it when `flee`, from the map's cache.
+// `target` is the creature on the goal, if the map is to follow it.
+fn cached(map : @mut Map, goal : &Position, flee : bool, target : Option<uint>) -> @DijkstraMap {
+	let goal = match map.normalize(goal) {
+		Some(p) => p,
+		None => *goal
+	};
+	let found = do map.flows.position |&f| {
+		f.flee == flee && f.target == target && (target.is_some() || f.goals == ~[goal])
+	};
+	match found {
+		Some(i) if map.flows[i].goals == ~[goal] => return map.flows[i],
+		// The creature has moved since
+		Some(i) => { map.flows.remove(i); },
+		None => {}
+	}
+
+	let mut towards = DijkstraMap::new(map, [goal]);
+	towards.target = target;
+	let flow = if flee {
+		@towards.inverted(map, FLEE_PERCENT)
+	} else {
+		@towards
+	};
+
+	if target.is_none() && map.flows.filtered(|&f| f.target.is_none()).len() >= FLOW_CACHE_SIZE {
+		match map.flows.position(|&f| f.target.is_none()) {
+			Some(oldest) => { map.flows.remove(oldest); },
+			None => {}
+		}
+	}
+	map.flows.push(flow);
+	flow
+}
+
+// Drop the maps following the creature `id`, which is leaving `map`
+pub fn forget_creature(map : &mut Map, id : uint) {
+	map.flows = map.flows.filtered(|&f| f.target != Some(id));
+}
+
+// Distance map towards `goal`, shared by everyone on the map
+pub fn flow_to(map : @mut Map, goal : &Position) -> @DijkstraMap {
+	cached(map, goal, false, None)
+}
+
+// Distance map towards `target` wherever it goes, shared by everyone on the map
+pub fn flow_to_creature(map : @mut Map, target : @mut Creature) -> @DijkstraMap {
+	cached(map, &target.pos, false, Some(target.id))
+}
+
+// Flee map away from `target` wherever it goes, shared by everyone on the map
+pub fn flow_from_creature(map : @mut Map, target : @mut Creature) -> @DijkstraMap {
+	cached(map, &target.pos, true, Some(target.id))
+}
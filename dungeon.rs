@@ -0,0 +1,108 @@
+use core::to_str::ToStr;
+
+use map;
+use map::{Map, Tile};
+use replay::Recorder;
+
+/**
+ * What happens to the monsters on levels the player is not on
+ */
+#[deriving(Eq)]
+pub enum LevelPolicy {
+	// Keep ticking them as usual
+	SIMULATE,
+	// Leave them as they are until the player comes back
+	FREEZE
+}
+
+pub impl LevelPolicy {
+	fn parse(s : &str) -> Option<LevelPolicy> {
+		match s {
+			"simulate" => Some(SIMULATE),
+			"freeze" => Some(FREEZE),
+			_ => None
+		}
+	}
+}
+
+impl ToStr for LevelPolicy {
+	fn to_str(&self) -> ~str {
+		match *self {
+			SIMULATE => ~"simulate",
+			FREEZE => ~"freeze"
+		}
+	}
+}
+
+// Put `stairs` on a free floor tile of `level` from which its `other` stairs can be reached
+fn add_stairs(level : @mut Map, stairs : Tile, other : Tile) {
+	match level.random_floor_position_reaching(other) {
+		Some(pos) => level.tiles[pos.x][pos.y] = stairs,
+		None => {}
+	}
+}
+
+/**
+ * Stack of maps connected with stairs. Level 0 is the top one.
+ */
+pub struct Dungeon {
+	levels : ~[@mut Map],
+	policy : LevelPolicy
+}
+
+pub impl Dungeon {
+	/**
+	 * Link `levels` together, adding stairs where a level has none, in
+	 * reach of the level's other stairs.
+	 * Every level shares the RNG, recorder, message log and creature ids of the first.
+	 */
+	fn new(levels : ~[@mut Map], policy : LevelPolicy) -> Dungeon {
+		let top = levels[0];
+
+		for levels.eachi |depth, &level| {
+			level.depth = depth;
+			// Daylight only reaches the top level
+			level.dark = depth > 0;
+			level.rng = top.rng;
+			level.recorder = top.recorder;
+			level.log = top.log;
+			level.next_creature_id = top.next_creature_id;
+
+			if depth > 0 {
+				let upper = levels[depth - 1];
+				level.upper = Some(upper);
+				upper.lower = Some(level);
+
+				if upper.find_tile(map::STAIRS_DOWN).is_none() {
+					add_stairs(upper, map::STAIRS_DOWN, map::STAIRS_UP);
+				}
+				if level.find_tile(map::STAIRS_UP).is_none() {
+					add_stairs(level, map::STAIRS_UP, map::STAIRS_DOWN);
+				}
+			}
+		}
+
+		Dungeon { levels: levels, policy: policy }
+	}
+
+	fn set_recorder(&mut self, recorder : @mut Recorder) {
+		for self.levels.each |&level| {
+			level.recorder = Some(recorder);
+		}
+	}
+
+	fn top(&self) -> @mut Map {
+		self.levels[0]
+	}
+
+	fn ticks(&self) -> uint {
+		self.levels[0].ticks
+	}
+
+	// Advance the game clock of every level
+	fn advance_clock(&mut self) {
+		for self.levels.each |&level| {
+			level.ticks += 1;
+		}
+	}
+}
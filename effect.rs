@@ -0,0 +1,81 @@
+use core::to_str::ToStr;
+
+use map::{Creature, HEROES};
+use combat;
+use status::StatusKind;
+
+/**
+ * Something that happens to a creature: drinking a potion, reading a
+ * scroll, and later stepping on traps or being hit by spells.
+ *
+ * Effects are plain data, written as text like "heal 8" in data files.
+ */
+pub enum Effect {
+	// Hit points back, up to the maximum
+	HEAL(int),
+	// Jump to a random free floor tile of the level
+	BLINK,
+	// Learn the whole map of the level
+	REVEAL_MAP,
+	// Status lasting a number of ticks
+	STATUS(StatusKind, uint)
+}
+
+pub impl Effect {
+	// Inverse of `to_str`
+	fn parse(s : &str) -> Option<Effect> {
+		let words = str::words(s);
+		match words.len() {
+			1 => match words[0] {
+				~"blink" => Some(BLINK),
+				~"reveal-map" => Some(REVEAL_MAP),
+				_ => None
+			},
+			2 => match (copy words[0], uint::from_str(words[1])) {
+				(~"heal", Some(n)) => Some(HEAL(n as int)),
+				(kind, Some(n)) => match StatusKind::parse(kind) {
+					Some(kind) => Some(STATUS(kind, n)),
+					None => None
+				},
+				_ => None
+			},
+			_ => None
+		}
+	}
+
+	/**
+	 * Let the effect happen to `cr` and log it
+	 */
+	fn apply(&self, cr : @mut Creature) {
+		match *self {
+			HEAL(n) => cr.heal(n),
+			BLINK => cr.blink(),
+			REVEAL_MAP => cr.reveal_map(),
+			STATUS(kind, ticks) => cr.add_status(kind, ticks)
+		}
+		cr.map.message(self.describe(cr));
+	}
+
+	// Message for the log when the effect happens to `cr`
+	fn describe(&self, cr : @mut Creature) -> ~str {
+		let name = combat::name(cr);
+		let you = cr.faction == HEROES;
+		match *self {
+			HEAL(_) => fmt!("%s %s better", name, if you { "feel" } else { "looks" }),
+			BLINK => fmt!("%s %s", name, if you { "blink" } else { "blinks" }),
+			REVEAL_MAP => fmt!("%s %s the lay of the land", name, if you { "know" } else { "knows" }),
+			STATUS(kind, _) => fmt!("%s %s %s", name, if you { "are" } else { "is" }, kind.adjective())
+		}
+	}
+}
+
+impl ToStr for Effect {
+	fn to_str(&self) -> ~str {
+		match *self {
+			HEAL(n) => fmt!("heal %d", n),
+			BLINK => ~"blink",
+			REVEAL_MAP => ~"reveal-map",
+			STATUS(kind, n) => fmt!("%s %u", kind.to_str(), n)
+		}
+	}
+}
@@ -0,0 +1,184 @@
+use core::managed;
+
+use map;
+use map::{Map, Creature};
+use dungeon;
+use dungeon::Dungeon;
+use species::SpeciesRegistry;
+
+/**
+ * Output side of the game loop: shows the state of the game and tells
+ * the loop when the user wants to quit.
+ */
+pub trait Renderer {
+	fn set_player(&mut self, player : @mut Creature);
+	fn update(&mut self);
+	fn check_exit_input(&mut self);
+	fn exit_requested(&self) -> bool;
+}
+
+/**
+ * Renderer that draws nothing. Used to run the simulation headless.
+ *
+ * Asks for exit as soon as the player is dead, as there is nobody
+ * to wait for.
+ */
+pub struct NullRenderer {
+	exit : bool
+}
+
+pub impl NullRenderer {
+	fn new() -> NullRenderer {
+		NullRenderer { exit: false }
+	}
+}
+
+impl Renderer for NullRenderer {
+	fn set_player(&mut self, _ : @mut Creature) {
+	}
+
+	fn update(&mut self) {
+	}
+
+	fn check_exit_input(&mut self) {
+		self.exit = true;
+	}
+
+	fn exit_requested(&self) -> bool {
+		self.exit
+	}
+}
+
+/**
+ * The simulation loop: ticks every creature in the dungeon and hands
+ * the result to a renderer.
+ */
+pub struct Engine {
+	dungeon : Dungeon,
+	renderer : @mut Renderer,
+	creatures : ~[@mut Creature],
+	player : Option<@mut Creature>,
+	// Check for exit every tick, when nobody is reading the player's input
+	poll_exit : bool
+}
+
+pub impl Engine {
+	fn new(dungeon : Dungeon, renderer : @mut Renderer) -> Engine {
+		Engine {
+			dungeon: dungeon,
+			renderer: renderer,
+			creatures: ~[],
+			player: None,
+			poll_exit: false
+		}
+	}
+
+	fn add_creature(&mut self, creature : @mut Creature) {
+		creature.update_visibility();
+		self.creatures.push(creature);
+	}
+
+	// Spawn `n` monsters from the spawn table of `level`
+	fn spawn_creatures<T:map::MoveController + 'static>(
+			&mut self, level : @mut Map, n : uint, registry : &SpeciesRegistry,
+			controller : &fn() -> @T
+			) {
+		for uint::range(0, n) |_| {
+			match level.spawn_random_species(registry, controller()) {
+				Some(creature) => self.add_creature(creature),
+				// No free floor left
+				None => break
+			}
+		}
+	}
+
+	fn set_player(&mut self, player : @mut Creature) {
+		self.add_creature(player);
+		self.follow_player(player);
+	}
+
+	// Make an already added creature the player
+	fn follow_player(&mut self, player : @mut Creature) {
+		self.player = Some(player);
+
+		player.update_visibility();
+		self.renderer.set_player(player);
+		self.renderer.update();
+	}
+
+	fn is_player(&self, creature : @mut Creature) -> bool {
+		match self.player {
+			Some(player) => managed::mut_ptr_eq(player, creature),
+			None => false
+		}
+	}
+
+	// Level the player is on
+	fn current_level(&self) -> @mut Map {
+		match self.player {
+			Some(player) => player.map,
+			None => self.dungeon.top()
+		}
+	}
+
+	// Is the creature ticked under the dungeon's level policy
+	fn is_active(&self, creature : @mut Creature) -> bool {
+		match self.dungeon.policy {
+			dungeon::SIMULATE => true,
+			dungeon::FREEZE => managed::mut_ptr_eq(creature.map, self.current_level())
+		}
+	}
+
+	// Advance the simulation by one tick. Returns false if the game should stop.
+	fn tick(&mut self) -> bool {
+		for self.creatures.each |&creature| {
+			if (!creature.alive() || !self.is_active(creature)) {
+				loop;
+			}
+
+			let redraw = creature.tick();
+
+			if (redraw) {
+				creature.update_visibility();
+			}
+
+			if (self.renderer.exit_requested()) {
+				return false;
+			}
+		}
+
+		self.dungeon.advance_clock();
+
+		match self.player {
+			Some(player) => {
+				if (self.poll_exit || !player.alive()) {
+					self.renderer.check_exit_input();
+				}
+			},
+			None => {}
+		}
+
+		self.renderer.update();
+
+		// Every shot has been shown by now
+		for self.dungeon.levels.each |&level| {
+			level.shots = ~[];
+		}
+
+		!self.renderer.exit_requested()
+	}
+
+	// Run until exit, or until `limit` ticks have passed. Returns number of ticks.
+	fn run(&mut self, limit : Option<uint>) -> uint {
+		loop {
+			match limit {
+				Some(n) if self.dungeon.ticks() >= n => break,
+				_ => {}
+			}
+			if (!self.tick()) {
+				break;
+			}
+		}
+		self.dungeon.ticks()
+	}
+}
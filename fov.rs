@@ -0,0 +1,303 @@
+use map;
+use map::{Position, Direction, DIRECTIONS};
+
+/*
+ * Field of view
+ *
+ * Symmetric shadowcasting, after Albert Ford's algorithm for square
+ * grids, carried over to hexes. The area around the origin is split
+ * into six sextants. Sextant k holds the hexes
+ *
+ *     depth * dir(k) + col * dir(k + 2),    0 <= col <= depth
+ *
+ * which are `depth` steps away from the origin, between the rays going
+ * in directions k and k + 1. dir(k) and dir(k + 2) are a basis of the
+ * hex grid, so a straight line in a sextant is a straight line in
+ * (depth, col) and the square grid algorithm works unchanged:
+ *
+ *  - a floor hex is visible when its center is in view and a wall when
+ *    any part of it is, which makes vision between floor hexes
+ *    symmetric and leaves no gaps along walls,
+ *  - each hex is visited once per sextant, so the cost is linear in
+ *    the visible area.
+ *
+ * Vision cones are made of half sextants, so they can be 60, 120, 180
+ * or 360 degrees wide around the direction a creature faces.
+ */
+
+// Slope num / den across a sextant: 0 on the ray of dir(k), 1 on dir(k + 1)
+struct Slope {
+	num : int,
+	den : int
+}
+
+fn floor_div(a : int, b : int) -> int {
+	if a >= 0 { a / b } else { -((b - a - 1) / b) }
+}
+
+pub impl Slope {
+	// Slope of the edge between col - 1 and col
+	fn of(depth : int, col : int) -> Slope {
+		Slope { num: 2 * col - 1, den: 2 * depth }
+	}
+
+	// First column at `depth` whose center is at or after the slope
+	fn first_col(&self, depth : int) -> int {
+		floor_div(2 * depth * self.num + self.den, 2 * self.den)
+	}
+
+	// Last column at `depth` whose center is at or before the slope
+	fn last_col(&self, depth : int) -> int {
+		-floor_div(self.den - 2 * depth * self.num, 2 * self.den)
+	}
+
+	fn before(&self, depth : int, col : int) -> bool {
+		depth * self.num <= col * self.den
+	}
+
+	fn after(&self, depth : int, col : int) -> bool {
+		col * self.den <= depth * self.num
+	}
+}
+
+fn offset(d : map::Direction) -> Position {
+	Position {x: 0, y: 0}.neighbor(d)
+}
+
+fn scan(origin : &Position, sextant : uint, depth : int, start : Slope, end : Slope,
+		radius : int, blocks : &fn(&Position) -> bool, reveal : &fn(&Position)) {
+	if depth > radius {
+		return;
+	}
+
+	let forward = offset(DIRECTIONS[sextant]);
+	let side = offset(DIRECTIONS[(sextant + 2) % 6]);
+	let mut start = start;
+	let mut prev_blocked = None;
+
+	for int::range(start.first_col(depth), end.last_col(depth) + 1) |col| {
+		let pos = Position {
+			x: origin.x + forward.x * depth + side.x * col,
+			y: origin.y + forward.y * depth + side.y * col
+		};
+		let blocked = blocks(&pos);
+
+		if blocked || (start.before(depth, col) && end.after(depth, col)) {
+			reveal(&pos);
+		}
+
+		match prev_blocked {
+			Some(true) if !blocked => {
+				start = Slope::of(depth, col);
+			},
+			Some(false) if blocked => {
+				scan(origin, sextant, depth + 1, start, Slope::of(depth, col),
+					radius, blocks, reveal);
+			},
+			_ => {}
+		}
+		prev_blocked = Some(blocked);
+	}
+
+	if prev_blocked == Some(false) {
+		scan(origin, sextant, depth + 1, start, end, radius, blocks, reveal);
+	}
+}
+
+/**
+ * How far and how wide a creature sees
+ */
+pub struct VisionProfile {
+	radius : uint,
+	// Width of the vision cone around the facing direction: 60, 120, 180 or 360 degrees
+	cone : uint,
+	// Radius lost on dark levels
+	dark_penalty : uint
+}
+
+pub static DEFAULT_VISION : VisionProfile = VisionProfile { radius: 6, cone: 120, dark_penalty: 3 };
+pub static PLAYER_VISION : VisionProfile = VisionProfile { radius: 10, cone: 180, dark_penalty: 5 };
+
+pub impl VisionProfile {
+	fn radius_in(&self, dark : bool) -> uint {
+		if !dark {
+			self.radius
+		} else if self.radius > self.dark_penalty {
+			self.radius - self.dark_penalty
+		} else {
+			1
+		}
+	}
+
+	// Profile with the next narrower cone
+	fn narrowed(&self) -> VisionProfile {
+		let cone = match self.cone {
+			360 => 180,
+			180 => 120,
+			_ => 60
+		};
+		VisionProfile { cone: cone, .. *self }
+	}
+
+	fn is_valid_cone(cone : uint) -> bool {
+		cone == 60 || cone == 120 || cone == 180 || cone == 360
+	}
+}
+
+/**
+ * Call `reveal` for every hex within `radius` steps of `origin` that is
+ * visible from it, including the origin. `blocks` tells which hexes
+ * stop sight.
+ *
+ * Positions are not wrapped around the map; `blocks` and `reveal` get
+ * them as they are. Hexes on the borders between sextants may be
+ * revealed twice.
+ */
+pub fn compute(origin : &Position, radius : uint,
+		blocks : &fn(&Position) -> bool, reveal : &fn(&Position)) {
+	compute_cone(origin, radius, map::N, 360, blocks, reveal);
+}
+
+/**
+ * Like `compute`, but only in a cone `cone` degrees wide centered on
+ * `dir`. `cone` is 60, 120, 180 or 360.
+ */
+pub fn compute_cone(origin : &Position, radius : uint, dir : Direction, cone : uint,
+		blocks : &fn(&Position) -> bool, reveal : &fn(&Position)) {
+	// Half cone width and half sextant centers in 15 degree units
+	let half_width = (cone / 30) as int;
+	let facing = 4 * dir.to_uint() as int;
+
+	reveal(origin);
+	for uint::range(0, 6) |sextant| {
+		for int::range(0, 2) |half| {
+			let mut center = (4 * sextant as int + 2 * half + 1 - facing) % 24;
+			if center < 0 {
+				center += 24;
+			}
+			if center > 12 {
+				center -= 24;
+			}
+			if int::abs(center) >= half_width {
+				loop;
+			}
+
+			let start = Slope { num: half, den: 2 };
+			let end = Slope { num: half + 1, den: 2 };
+			scan(origin, sextant, 1, start, end, radius as int, blocks, reveal);
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use core::to_str::ToStr;
+
+	use map;
+	use map::{Position, Tile, DIRECTIONS};
+	use mapfile;
+	use fov::compute;
+
+	// Distinct hexes revealed from `origin`
+	fn visible(origin : &Position, radius : uint, blocks : &fn(&Position) -> bool) -> ~[Position] {
+		let mut seen = ~[];
+		do compute(origin, radius, blocks) |p| {
+			if !seen.contains(p) {
+				seen.push(*p);
+			}
+		}
+		seen
+	}
+
+	// Hexes `dist` steps away from `center`, in order around it
+	fn ring(center : &Position, dist : int) -> ~[Position] {
+		let mut pos = *center;
+		for int::range(0, dist) |_| {
+			pos = pos.neighbor(map::SW);
+		}
+		let mut ring = ~[];
+		for DIRECTIONS.each |&d| {
+			for int::range(0, dist) |_| {
+				ring.push(pos);
+				pos = pos.neighbor(d);
+			}
+		}
+		ring
+	}
+
+	fn opaque(tiles : &~[~[Tile]], p : &Position) -> bool {
+		p.x < 0 || p.y < 0 || p.x >= tiles.len() as int || p.y >= tiles[0].len() as int ||
+			!tiles[p.x][p.y].can_see_through()
+	}
+
+	#[test]
+	fn test_radius() {
+		let origin = Position {x: 0, y: 0};
+		for uint::range(0, 6) |radius| {
+			let seen = visible(&origin, radius, |_| false);
+			assert!(seen.all(|p| origin.distance(p) <= radius as int));
+			assert!(seen.len() == 1 + 3 * radius * (radius + 1));
+		}
+	}
+
+	#[test]
+	fn test_pillar_shadow() {
+		let origin = Position {x: 0, y: 0};
+		for DIRECTIONS.each |&d| {
+			for int::range(1, 4) |steps| {
+				let step = origin.neighbor(d);
+				let pillar = Position {x: step.x * steps, y: step.y * steps};
+				let seen = visible(&origin, 8, |p| *p == pillar);
+				assert!(seen.contains(&pillar));
+
+				for int::range(1, 9) |dist| {
+					let ring = ring(&origin, dist);
+					let hidden = ring.map(|p| !seen.contains(p));
+					let mut changes = 0;
+					for uint::range(0, ring.len()) |i| {
+						if hidden[i] != hidden[(i + ring.len() - 1) % ring.len()] {
+							changes += 1;
+						}
+					}
+					if dist <= steps {
+						assert!(!hidden.any(|&h| h));
+					} else {
+						// One shadow without gaps, all the way out
+						assert!(changes == 2);
+					}
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn test_symmetry() {
+		let text = ~"#########\n" +
+			"#...#...#\n" +
+			"#.#...#.#\n" +
+			"#...+...#\n" +
+			"#.##..#.#\n" +
+			"#.......#\n" +
+			"#########\n";
+		let tiles = match mapfile::parse(text) {
+			Ok(file) => file.tiles,
+			Err(e) => fail!(e.to_str())
+		};
+
+		let mut floors = ~[];
+		for uint::range(0, tiles.len()) |x| {
+			for uint::range(0, tiles[0].len()) |y| {
+				if tiles[x][y].is_floor() {
+					floors.push(Position {x: x as int, y: y as int});
+				}
+			}
+		}
+		let seen = floors.map(|a| visible(a, 6, |p| opaque(&tiles, p)));
+
+		for uint::range(0, floors.len()) |a| {
+			for uint::range(0, floors.len()) |b| {
+				assert!(seen[a].contains(&floors[b]) == seen[b].contains(&floors[a]));
+			}
+		}
+	}
+}
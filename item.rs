@@ -0,0 +1,118 @@
+use core::to_str::ToStr;
+
+use rng::GameRng;
+use effect;
+use effect::Effect;
+use status;
+
+/**
+ * Where on a creature an item is worn
+ */
+#[deriving(Eq)]
+pub enum Slot {
+	WEAPON,
+	SHIELD,
+	HEAD,
+	BODY
+}
+
+pub static SLOTS : [Slot, ..4] = [WEAPON, SHIELD, HEAD, BODY];
+
+/**
+ * Thing lying on the map or carried by a creature.
+ *
+ * Items are copies of entries in `CATALOGUE`, so save files only need
+ * to store their names.
+ */
+pub struct Item {
+	name : &'static str,
+	// Index of the item's sprite, counted along the rows of item sprites
+	sprite : uint,
+	// Where it's worn, None if it can't be
+	slot : Option<Slot>,
+	// Added to the wearer's attack and defence
+	attack : int,
+	defence : int,
+	// Added to the chance of blocking attacks from the front, in percent
+	block : int,
+	// Narrows the wearer's vision cone by one step
+	narrows_vision : bool,
+	// What using it does; it's used up then
+	effect : Option<Effect>
+}
+
+pub static CATALOGUE : [Item, ..13] = [
+	Item { name: "gold coins", sprite: 0, slot: None,
+		attack: 0, defence: 0, block: 0, narrows_vision: false, effect: None },
+	Item { name: "dagger", sprite: 1, slot: Some(WEAPON),
+		attack: 1, defence: 0, block: 0, narrows_vision: false, effect: None },
+	Item { name: "sword", sprite: 2, slot: Some(WEAPON),
+		attack: 3, defence: 0, block: 0, narrows_vision: false, effect: None },
+	Item { name: "shield", sprite: 3, slot: Some(SHIELD),
+		attack: 0, defence: 0, block: 30, narrows_vision: false, effect: None },
+	Item { name: "helmet", sprite: 4, slot: Some(HEAD),
+		attack: 0, defence: 1, block: 0, narrows_vision: true, effect: None },
+	Item { name: "bone", sprite: 5, slot: None,
+		attack: 0, defence: 0, block: 0, narrows_vision: false, effect: None },
+	Item { name: "leather armour", sprite: 6, slot: Some(BODY),
+		attack: 0, defence: 1, block: 0, narrows_vision: false, effect: None },
+	Item { name: "potion of healing", sprite: 8, slot: None,
+		attack: 0, defence: 0, block: 0, narrows_vision: false, effect: Some(effect::HEAL(10)) },
+	Item { name: "potion of speed", sprite: 9, slot: None,
+		attack: 0, defence: 0, block: 0, narrows_vision: false, effect: Some(effect::STATUS(status::HASTE, 300)) },
+	Item { name: "potion of confusion", sprite: 10, slot: None,
+		attack: 0, defence: 0, block: 0, narrows_vision: false, effect: Some(effect::STATUS(status::CONFUSION, 150)) },
+	Item { name: "scroll of blinking", sprite: 11, slot: None,
+		attack: 0, defence: 0, block: 0, narrows_vision: false, effect: Some(effect::BLINK) },
+	Item { name: "scroll of magic mapping", sprite: 12, slot: None,
+		attack: 0, defence: 0, block: 0, narrows_vision: false, effect: Some(effect::REVEAL_MAP) },
+	Item { name: "potion of regeneration", sprite: 13, slot: None,
+		attack: 0, defence: 0, block: 0, narrows_vision: false,
+		effect: Some(effect::STATUS(status::REGENERATION, 400)) }
+];
+
+// Items a creature can carry
+pub static INVENTORY_SIZE : uint = 10;
+
+pub impl Slot {
+	fn to_uint(&self) -> uint {
+		*self as uint
+	}
+
+	fn parse(s : &str) -> Option<Slot> {
+		match s {
+			"weapon" => Some(WEAPON),
+			"shield" => Some(SHIELD),
+			"head" => Some(HEAD),
+			"body" => Some(BODY),
+			_ => None
+		}
+	}
+}
+
+impl ToStr for Slot {
+	fn to_str(&self) -> ~str {
+		match *self {
+			WEAPON => ~"weapon",
+			SHIELD => ~"shield",
+			HEAD => ~"head",
+			BODY => ~"body"
+		}
+	}
+}
+
+pub impl Item {
+	// Item of the catalogue called `name`
+	fn find(name : &str) -> Option<Item> {
+		for CATALOGUE.each |item| {
+			if item.name == name {
+				return Some(*item);
+			}
+		}
+		None
+	}
+
+	fn random(rng : @mut GameRng) -> Item {
+		CATALOGUE[rng.gen_uint_range(0, CATALOGUE.len())]
+	}
+}
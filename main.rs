@@ -1,118 +1,501 @@
+extern mod std;
 extern mod sdl;
 
+// Declared first, so its macros are there for the modules below
+#[macro_escape]
+pub mod parse;
 pub mod map;
 pub mod ui;
+pub mod engine;
+pub mod rng;
+pub mod replay;
+pub mod save;
+pub mod mapgen;
+pub mod mapfile;
+pub mod dungeon;
+pub mod fov;
+pub mod ai;
+pub mod path;
+pub mod dijkstra;
+pub mod travel;
+pub mod combat;
+pub mod messages;
+pub mod item;
+pub mod effect;
+pub mod status;
+pub mod species;
 
 use map::MapView;
-use core::rand::RngUtil;
+use ai::MonsterController;
 
 pub struct PlayerController {
-	ui : @mut ui::UI
+	ui : @mut ui::UI,
+	travel : @mut travel::Travel
 }
 
-pub struct MonsterController(());
-
-impl MonsterController {
-	fn new() -> MonsterController {
-		 MonsterController(())
+impl PlayerController {
+	fn new(ui : @mut ui::UI) -> PlayerController {
+		PlayerController {ui: ui, travel: @mut travel::Travel::new()}
 	}
 }
 
-impl map::MoveController for MonsterController {
+impl map::MoveController for PlayerController {
 	fn get_move(&mut self, cr : @mut map::Creature) -> map::Action {
-		let rng = rand::Rng();
+		// Any key stops travelling
+		if self.travel.is_active() && self.ui.key_pressed() {
+			self.travel.stop();
+		}
 
-		for [map::FORWARD, map::LEFT, map::RIGHT].each |dir| {
-			let pos = cr.pos;
-			let cd = cr.dir;
-			let pos = pos.neighbor(cd.turn(*dir));
-			match cr.map.creature_at(&pos) {
+		loop {
+			match self.travel.next_action(cr) {
+				Some(action) => return action,
 				None => {}
-				Some(_) => {
-					return map::MELEE(*dir);
-				}
 			}
-		};
-
-		match rng.gen_int_range(0, 10) {
-			0 => map::TURN(map::LEFT),
-			1 => map::TURN(map::RIGHT),
-			_ => {
-				let cd = cr.dir;
-				let pos = cr.pos;
-				let front = pos.neighbor(cd);
-				let in_front = cr.map.at(&front);
-				if in_front.is_passable() {
-					map::MOVE(map::FORWARD)
-				} else {
-					map::TURN(map::LEFT)
-				}
+			match self.ui.get_command() {
+				ui::ACT(action) => return action,
+				ui::TRAVEL(target) => self.travel.start(cr, &target),
+				ui::EXPLORE => self.travel.explore(cr)
 			}
 		}
 	}
-}
 
-impl PlayerController {
-	fn new(ui : @mut ui::UI) -> PlayerController {
-		PlayerController {ui: ui}
+	fn type_tag(&self) -> &'static str {
+		"player"
 	}
 }
 
-impl map::MoveController for PlayerController {
-	fn get_move(&mut self, _ : @mut map::Creature) -> map::Action {
-		self.ui.get_input()
+
+struct Options {
+	headless : bool,
+	ticks : Option<uint>,
+	seed : Option<u32>,
+	record : Option<~str>,
+	replay : Option<~str>,
+	save : Option<~str>,
+	load : Option<~str>,
+	generator : ~str,
+	width : uint,
+	height : uint,
+	topology : map::Topology,
+	depth : uint,
+	policy : dungeon::LevelPolicy,
+	export_map : Option<~str>
+}
+
+fn usage() -> ! {
+	io::println("usage: rustyhex [--headless] [--ticks N] [--seed N] \
+		[--record FILE | --replay FILE] [--save FILE] [--load FILE] \
+		[--map random|caves|rooms|file:FILE] [--size WIDTHxHEIGHT] \
+		[--topology torus|bounded] [--depth N] [--levels simulate|freeze] \
+		[--export-map FILE]");
+	fail!(~"invalid arguments");
+}
+
+// Value of the option at args[i]
+fn arg_value(args : &[~str], i : uint) -> ~str {
+	if i + 1 >= args.len() {
+		usage();
 	}
+	copy args[i + 1]
 }
 
+fn parse_args(args : &[~str]) -> Options {
+	let mut opts = Options {
+		headless: false, ticks: None, seed: None,
+		record: None, replay: None,
+		save: None, load: None,
+		generator: ~"caves",
+		width: map::DEFAULT_MAP_WIDTH,
+		height: map::DEFAULT_MAP_HEIGHT,
+		topology: map::TORUS,
+		depth: 3,
+		policy: dungeon::FREEZE,
+		export_map: None
+	};
+	let mut i = 1;
+
+	while i < args.len() {
+		match args[i] {
+			~"--headless" => {
+				opts.headless = true;
+			},
+			~"--ticks" => {
+				opts.ticks = match uint::from_str(arg_value(args, i)) {
+					Some(n) => Some(n),
+					None => usage()
+				};
+				i += 1;
+			},
+			~"--seed" => {
+				opts.seed = match u32::from_str(arg_value(args, i)) {
+					Some(n) => Some(n),
+					None => usage()
+				};
+				i += 1;
+			},
+			~"--record" => {
+				opts.record = Some(arg_value(args, i));
+				i += 1;
+			},
+			~"--replay" => {
+				opts.replay = Some(arg_value(args, i));
+				i += 1;
+			},
+			~"--save" => {
+				opts.save = Some(arg_value(args, i));
+				i += 1;
+			},
+			~"--load" => {
+				opts.load = Some(arg_value(args, i));
+				i += 1;
+			},
+			~"--map" => {
+				opts.generator = arg_value(args, i);
+				i += 1;
+			},
+			~"--topology" => {
+				opts.topology = match map::Topology::parse(arg_value(args, i)) {
+					Some(t) => t,
+					None => usage()
+				};
+				i += 1;
+			},
+			~"--depth" => {
+				opts.depth = match uint::from_str(arg_value(args, i)) {
+					Some(n) if n > 0 => n,
+					_ => usage()
+				};
+				i += 1;
+			},
+			~"--levels" => {
+				opts.policy = match dungeon::LevelPolicy::parse(arg_value(args, i)) {
+					Some(p) => p,
+					None => usage()
+				};
+				i += 1;
+			},
+			~"--export-map" => {
+				opts.export_map = Some(arg_value(args, i));
+				i += 1;
+			},
+			~"--size" => {
+				let size = str::split_char(arg_value(args, i), 'x');
+				if size.len() != 2 {
+					usage();
+				}
+				match (uint::from_str(size[0]), uint::from_str(size[1])) {
+					(Some(w), Some(h)) if w >= 8 && h >= 8 => {
+						opts.width = w;
+						opts.height = h;
+					},
+					_ => usage()
+				}
+				i += 1;
+			},
+			_ => usage()
+		}
+		i += 1;
+	}
+
+	// Two rooms need some space to fit
+	if opts.generator == ~"rooms" &&
+			(opts.width < mapgen::ROOMS_MIN_SIZE || opts.height < mapgen::ROOMS_MIN_SIZE) {
+		usage();
+	}
 
-fn sdl_main() {
-	let mut ui = @mut ui::UI::new();
+	// Recordings start from a fresh map generated from the seed
+	if opts.load.is_some() && (opts.record.is_some() || opts.replay.is_some()) {
+		usage();
+	}
+	opts
+}
 
-	let map = @mut map::Map::new();
+fn load_replay(opts : &Options) -> Option<@mut replay::Replay> {
+	match opts.replay {
+		Some(ref path) => {
+			match replay::Replay::load(&Path(*path)) {
+				Ok(r) => Some(@mut r),
+				Err(e) => fail!(fmt!("can't load replay %s: %s", *path, e))
+			}
+		},
+		None => None
+	}
+}
 
+// Everything needed to generate the same starting dungeon again
+struct Setup {
+	seed : u32,
+	generator : ~str,
+	width : uint,
+	height : uint,
+	topology : map::Topology,
+	depth : uint,
+	policy : dungeon::LevelPolicy
+}
 
-	let mut creatures = vec::from_fn(30, |_| {
-			map.spawn_random_creature(@MonsterController::new())
+fn game_setup(opts : &Options, replay : Option<@mut replay::Replay>) -> Setup {
+	match replay {
+		Some(r) => Setup {
+			seed: r.seed, generator: copy r.generator,
+			width: r.width, height: r.height,
+			topology: r.topology,
+			depth: r.depth, policy: r.policy
+		},
+		None => Setup {
+			seed: match opts.seed {
+				Some(seed) => seed,
+				None => rng::GameRng::random_seed()
+			},
+			generator: copy opts.generator,
+			width: opts.width, height: opts.height,
+			topology: opts.topology,
+			depth: opts.depth, policy: opts.policy
 		}
-	);
+	}
+}
+
+// Number of ticks to run for
+fn tick_limit(opts : &Options, replay : Option<@mut replay::Replay>) -> Option<uint> {
+	match (opts.ticks, replay) {
+		(Some(n), _) => Some(n),
+		(None, Some(r)) => r.end,
+		(None, None) => None
+	}
+}
+
+// Put the player on a random free tile from which the stairs down can be reached
+fn spawn_player<T:map::MoveController + 'static>(map : @mut map::Map, player : @T) -> @mut map::Creature {
+	let dir = map::N.turn_by_int(map.rng.gen_int_range(0, 6));
+	let spawn = match map.random_floor_position_reaching(map::STAIRS_DOWN) {
+		Some(pos) => map.spawn_creature(&pos, dir, player),
+		None => None
+	};
+	let spawn = match spawn {
+		Some(cr) => Some(cr),
+		None => map.spawn_random_creature(player)
+	};
+	match spawn {
+		Some(cr) => cr,
+		None => fail!(~"no free floor for the player")
+	}
+}
+
+fn new_game<T:map::MoveController + 'static>(
+		opts : &Options, setup : &Setup, renderer : @mut engine::Renderer, player : @T
+		) -> engine::Engine {
+	io::println(fmt!("seed: %u", setup.seed as uint));
 
-	let player = map.spawn_random_creature(@PlayerController::new(ui));
-	creatures.push(player);
+	let rng = @mut rng::GameRng::new(setup.seed);
+
+	let file = if setup.generator.starts_with("file:") {
+		let path = setup.generator.slice(5, setup.generator.len());
+		match mapfile::load(&Path(path)) {
+			Ok(file) => Some(file),
+			Err(e) => fail!(fmt!("can't load map %s", e))
+		}
+	} else {
+		None
+	};
 
-	player.update_visibility();
-	ui.set_player(player);
-	ui.update();
+	// A map file is the top level, the ones below it are caves of the requested size
+	let generator = match file {
+		Some(_) => mapgen::generator("caves"),
+		None => mapgen::generator(setup.generator)
+	};
+	let generator = match generator {
+		Some(generator) => generator,
+		None => usage()
+	};
+	let mut levels = ~[];
+	match file {
+		Some(ref file) => levels.push(
+			@mut map::Map::from_tiles(copy file.tiles, setup.topology, rng)),
+		None => {}
+	}
+	while levels.len() < setup.depth {
+		levels.push(@mut map::Map::generate(setup.width, setup.height, setup.topology,
+			&mut *generator, rng));
+	}
+	let mut engine = engine::Engine::new(dungeon::Dungeon::new(levels, setup.policy), renderer);
+	let map = engine.dungeon.top();
 
-	loop {
-		for creatures.each |creature| {
-			if (!creature.alive()) {
-				loop;
+	match opts.record {
+		Some(ref path) => {
+			match replay::Recorder::create(&Path(*path), setup.seed,
+					setup.generator, setup.width, setup.height, setup.topology,
+					setup.depth, setup.policy) {
+				Ok(recorder) => engine.dungeon.set_recorder(@mut recorder),
+				Err(e) => fail!(fmt!("can't record to %s: %s", *path, e))
 			}
-			if creature.pos == player.pos {
-				let redraw = creature.tick();
+		},
+		None => {}
+	}
+
+	let registry = match species::SpeciesRegistry::load(&Path(species::DEFAULT_SPECIES_PATH)) {
+		Ok(registry) => registry,
+		Err(e) => fail!(fmt!("can't load species %s", e))
+	};
+
+	let levels = copy engine.dungeon.levels;
+	for levels.eachi |depth, &level| {
+		// 10 items on a default sized map
+		level.scatter_items(uint::max(1, 10 * level.width * level.height /
+			(map::DEFAULT_MAP_WIDTH * map::DEFAULT_MAP_HEIGHT)));
 
-				if (redraw) {
-					player.update_visibility();
+		if depth == 0 && file.is_some() {
+			loop;
+		}
+		// 30 monsters on a default sized map
+		let monsters = uint::max(1, 30 * level.width * level.height /
+			(map::DEFAULT_MAP_WIDTH * map::DEFAULT_MAP_HEIGHT));
+		engine.spawn_creatures(level, monsters, &registry, || @MonsterController::new());
+	}
+
+	let player = match file {
+		Some(ref file) => {
+			for file.monsters.each |pos| {
+				let dir = map::N.turn_by_int(rng.gen_int_range(0, 6));
+				match map.spawn_creature(pos, dir, @MonsterController::new()) {
+					Some(cr) => {
+						cr.set_species(registry.pick(map.depth, rng));
+						engine.add_creature(cr);
+					},
+					None => {}
 				}
+			}
+
+			let spawn = if file.spawns.is_empty() {
+				None
 			} else {
-				creature.tick();
+				let pos = &file.spawns[rng.gen_uint_range(0, file.spawns.len())];
+				map.spawn_creature(pos, map::N, player)
 			};
+			match spawn {
+				Some(cr) => cr,
+				None => spawn_player(map, player)
+			}
+		},
+		None => spawn_player(map, player)
+	};
+	player.vision = fov::PLAYER_VISION;
+	player.faction = map::HEROES;
+	player.set_stats(combat::PLAYER_STATS);
+
+	match map.recorder {
+		Some(recorder) => recorder.set_player(player),
+		None => {}
+	}
 
-			if (ui.exit) {
-				return;
+	match opts.export_map {
+		Some(ref path) => {
+			match mapfile::export(&Path(*path), map, Some(player)) {
+				Ok(()) => {},
+				Err(e) => io::println(fmt!("can't export map to %s: %s", *path, e))
 			}
-		}
+		},
+		None => {}
+	}
 
-		if (!player.alive()) {
-			ui.check_exit_input();
-		}
+	engine.set_player(player);
+
+	engine
+}
+
+fn load_game(path : &str, renderer : @mut engine::Renderer,
+		controller : &fn(&str) -> Option<@map::MoveController>
+		) -> engine::Engine {
+	match save::load_game(&Path(path), renderer, controller) {
+		Ok(engine) => engine,
+		Err(e) => fail!(fmt!("can't load %s: %s", path, e))
+	}
+}
+
+fn end_game(opts : &Options, engine : &engine::Engine) {
+	match engine.dungeon.top().recorder {
+		Some(recorder) => recorder.finish(engine.dungeon.ticks()),
+		None => {}
+	}
 
-		ui.update();
+	match opts.save {
+		Some(ref path) => {
+			if engine.player.get().alive() {
+				match save::save_game(&Path(*path), engine) {
+					Ok(()) => io::println(fmt!("game saved to %s", *path)),
+					Err(e) => io::println(fmt!("can't save to %s: %s", *path, e))
+				}
+			}
+		},
+		None => {}
 	}
 }
 
+fn sdl_main(opts : &Options) {
+	let ui = @mut ui::UI::new();
+	let renderer = ui as @mut engine::Renderer;
+	let replay = load_replay(opts);
+
+	let mut engine = match (&opts.load, replay) {
+		(&Some(ref path), _) => do load_game(*path, renderer) |tag| {
+			match tag {
+				"player" | "replay" => Some(@PlayerController::new(ui) as @map::MoveController),
+				"monster" => Some(@MonsterController::new() as @map::MoveController),
+				_ => None
+			}
+		},
+		(&None, Some(r)) => {
+			let mut engine = new_game(opts, &game_setup(opts, replay), renderer,
+				@replay::ReplayController::new(r));
+			engine.poll_exit = true;
+			engine
+		},
+		(&None, None) => new_game(opts, &game_setup(opts, None), renderer,
+			@PlayerController::new(ui))
+	};
+
+	engine.run(tick_limit(opts, replay));
+	end_game(opts, &engine);
+}
+
+fn headless_main(opts : &Options) {
+	let renderer = @mut engine::NullRenderer::new() as @mut engine::Renderer;
+	let replay = load_replay(opts);
+
+	let mut engine = match (&opts.load, replay) {
+		(&Some(ref path), _) => do load_game(*path, renderer) |tag| {
+			match tag {
+				"player" | "replay" | "monster" =>
+					Some(@MonsterController::new() as @map::MoveController),
+				_ => None
+			}
+		},
+		(&None, Some(r)) => new_game(opts, &game_setup(opts, replay), renderer,
+			@replay::ReplayController::new(r)),
+		(&None, None) => {
+			// The player's AI has a generator of its own, so that replaying its
+			// recorded actions without it leaves the monsters' choices the same
+			let setup = game_setup(opts, None);
+			let rng = @mut rng::GameRng::new(!setup.seed);
+			new_game(opts, &setup, renderer, @MonsterController::with_rng(rng))
+		}
+	};
+
+	let ticks = engine.run(tick_limit(opts, replay));
+	end_game(opts, &engine);
+
+	let player = engine.player.get();
+
+	io::println(fmt!("%u ticks, player %s", ticks,
+		if player.alive() { "alive" } else { "dead" }));
+}
+
 fn main() {
-	do sdl::start {
-		sdl_main();
+	let opts = parse_args(os::args());
+
+	if opts.headless {
+		headless_main(&opts);
+	} else {
+		do sdl::start {
+			sdl_main(&opts);
+		}
 	}
 }
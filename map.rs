@@ -1,10 +1,29 @@
 use core::int::*;
 use core::cast;
-use core::rand;
-use core::rand::RngUtil;
 use core::cmp::Eq;
 use core::ops::{Add, Sub};
 use core::vec;
+use core::str;
+use core::to_str::ToStr;
+use core::util;
+
+use rng::GameRng;
+use replay::Recorder;
+use mapgen::MapGenerator;
+use fov;
+use fov::VisionProfile;
+use dijkstra;
+use dijkstra::DijkstraMap;
+use combat;
+use combat::{CombatResult, Stats};
+use messages::MessageLog;
+use item;
+use item::{Item, Slot};
+use status;
+use status::{Status, StatusKind};
+use effect::Effect;
+use species;
+use species::{Behaviour, Species, SpeciesRegistry};
 
 #[deriving(Eq)]
 pub enum Direction {
@@ -16,6 +35,9 @@ pub enum Direction {
 	NW
 }
 
+// Every direction, clockwise from N
+pub static DIRECTIONS : [Direction, ..6] = [N, NE, SE, S, SW, NW];
+
 pub struct Position {
 	x : int,
 	y : int
@@ -32,48 +54,130 @@ pub enum Action {
 	MOVE(RelativeDir),
 	TURN(RelativeDir),
 	MELEE(RelativeDir),
-	WAIT
+	// Shoot at the hex at this offset from the creature
+	FIRE(Position),
+	WAIT,
+	// Take the stairs the creature stands on
+	DESCEND,
+	ASCEND,
+	// Pick up the top item where the creature stands
+	PICKUP,
+	// Drop the item at this index of the inventory
+	DROP(uint),
+	// Wear or wield the item at this index of the inventory
+	EQUIP(uint),
+	// Put the item in a slot back into the inventory
+	UNEQUIP(Slot),
+	// Drink, read, ... the item at this index of the inventory
+	USE(uint)
+}
+
+/**
+ * Side a creature is on. Creatures only fight the other side.
+ */
+#[deriving(Eq)]
+pub enum Faction {
+	HEROES,
+	MONSTERS
 }
 
 pub trait MoveController {
 	fn get_move(&mut self, cr: @mut Creature) -> Action;
+	// Stable name of the controller type, stored in save files
+	fn type_tag(&self) -> &'static str;
 }
 
 pub struct Creature {
+	id : uint,
 	map : @mut Map,
 	pos : Position,
 	dir : Direction,
 	last_hit_time : int,
 	life : int,
+	stats : Stats,
+	// What messages call it
+	name : ~str,
+	// Percentage of the usual time actions take
+	speed : uint,
+	// Happens to creatures it hits
+	on_hit : Option<Effect>,
+	behaviour : Behaviour,
+	// Row of its sprites in tiles.png
+	sprite : uint,
 	controller : @MoveController,
 	action : Option<Action>,
 	pre_action_ticks : uint,
 	post_action_ticks : uint,
 	map_visible : ~[ ~[ bool ] ],
 	map_known : ~[ ~[ bool ] ],
+	// map_known of the other dungeon levels, indexed by depth
+	level_memory : ~[ Option<~[ ~[ bool ] ]> ],
+	inventory : ~[Item],
+	// Item worn in every slot, indexed by `Slot::to_uint`
+	equipment : ~[Option<Item>],
+	statuses : ~[Status],
+	vision : VisionProfile,
+	faction : Faction,
 	map_height: uint,
 	map_width: uint
 }
 
+#[deriving(Eq)]
 pub enum Tile {
 	FLOOR,
-	WALL
+	WALL,
+	DOOR,
+	STAIRS_DOWN,
+	STAIRS_UP
 }
 
-static MAP_WIDTH : uint = 32;
-static MAP_HEIGHT : uint = 32;
+/**
+ * What is beyond the edges of a map
+ */
+#[deriving(Eq)]
+pub enum Topology {
+	// Edges wrap around to the other side
+	TORUS,
+	// Nothing: positions outside read as walls
+	BOUNDED
+}
+
+pub static DEFAULT_MAP_WIDTH : uint = 32;
+pub static DEFAULT_MAP_HEIGHT : uint = 32;
 
 pub struct Map {
 	tiles : ~[ ~[ Tile ] ],
 	creatures : ~[ ~[ Option<@mut Creature> ] ],
+	// Stack of items on every tile, the top one last
+	items : ~[ ~[ ~[Item] ] ],
 	width : uint,
-	height : uint
+	height : uint,
+	topology : Topology,
+	rng : @mut GameRng,
+	ticks : uint,
+	// Shared by all levels of a dungeon
+	next_creature_id : @mut uint,
+	recorder : Option<@mut Recorder>,
+	// Shared by all levels of a dungeon
+	log : @mut MessageLog,
+	depth : uint,
+	// Unlit levels shorten the sight of creatures on them
+	dark : bool,
+	// Paths of the shots fired this tick, for the renderer to animate
+	shots : ~[~[Position]],
+	// Distance maps in use, see `dijkstra::cached`
+	flows : ~[@DijkstraMap],
+	upper : Option<@mut Map>,
+	lower : Option<@mut Map>
 }
 
 pub trait MapView {
 	fn at(&mut self, pos: &Position) -> Tile;
 	fn creature_at(&mut self, pos: &Position) -> Option<@mut Creature>;
 	fn translate(&self, pos : &Position) -> Position;
+	// Position of the tile in the underlying map, the same for every
+	// position of a tile on a torus. None beyond the edge of the map.
+	fn canonical(&self, pos : &Position) -> Option<Position>;
 }
 
 /**
@@ -92,7 +196,14 @@ pub impl Action {
 			MOVE(_) => 10u,
 			TURN(_) => 5u,
 			MELEE(_) => 4u,
-			WAIT => 1u
+			FIRE(_) => 12u,
+			WAIT => 1u,
+			DESCEND | ASCEND => 10u,
+			PICKUP => 5u,
+			DROP(_) => 3u,
+			EQUIP(_) => 10u,
+			UNEQUIP(_) => 8u,
+			USE(_) => 5u
 		}
 	}
 	fn post_ticks(&self) -> uint {
@@ -100,7 +211,81 @@ pub impl Action {
 			MOVE(_) => 10u,
 			TURN(_) => 5u,
 			MELEE(_) => 8u,
-			WAIT => 0u
+			FIRE(_) => 10u,
+			WAIT => 0u,
+			DESCEND | ASCEND => 10u,
+			PICKUP => 5u,
+			DROP(_) => 3u,
+			EQUIP(_) => 10u,
+			UNEQUIP(_) => 5u,
+			USE(_) => 5u
+		}
+	}
+
+	// Inverse of `to_str`
+	fn parse(s : &str) -> Option<Action> {
+		let words = str::words(s);
+		if words.len() == 1 {
+			return match words[0] {
+				~"WAIT" => Some(WAIT),
+				~"DESCEND" => Some(DESCEND),
+				~"ASCEND" => Some(ASCEND),
+				~"PICKUP" => Some(PICKUP),
+				_ => None
+			};
+		}
+		if words.len() == 2 && (words[0] == ~"DROP" || words[0] == ~"EQUIP" || words[0] == ~"USE") {
+			return match uint::from_str(words[1]) {
+				Some(i) if words[0] == ~"DROP" => Some(DROP(i)),
+				Some(i) if words[0] == ~"EQUIP" => Some(EQUIP(i)),
+				Some(i) => Some(USE(i)),
+				None => None
+			};
+		}
+		if words.len() == 2 && words[0] == ~"UNEQUIP" {
+			return match Slot::parse(words[1]) {
+				Some(slot) => Some(UNEQUIP(slot)),
+				None => None
+			};
+		}
+		if words.len() == 3 && words[0] == ~"FIRE" {
+			return match (int::from_str(words[1]), int::from_str(words[2])) {
+				(Some(x), Some(y)) => Some(FIRE(Position {x: x, y: y})),
+				_ => None
+			};
+		}
+		if words.len() != 2 {
+			return None;
+		}
+		match RelativeDir::parse(words[1]) {
+			Some(d) => {
+				match words[0] {
+					~"MOVE" => Some(MOVE(d)),
+					~"TURN" => Some(TURN(d)),
+					~"MELEE" => Some(MELEE(d)),
+					_ => None
+				}
+			},
+			None => None
+		}
+	}
+}
+
+impl ToStr for Action {
+	fn to_str(&self) -> ~str {
+		match *self {
+			MOVE(d) => ~"MOVE " + d.to_str(),
+			TURN(d) => ~"TURN " + d.to_str(),
+			MELEE(d) => ~"MELEE " + d.to_str(),
+			FIRE(offset) => fmt!("FIRE %d %d", offset.x, offset.y),
+			WAIT => ~"WAIT",
+			DESCEND => ~"DESCEND",
+			ASCEND => ~"ASCEND",
+			PICKUP => ~"PICKUP",
+			DROP(i) => fmt!("DROP %u", i),
+			EQUIP(i) => fmt!("EQUIP %u", i),
+			UNEQUIP(slot) => ~"UNEQUIP " + slot.to_str(),
+			USE(i) => fmt!("USE %u", i)
 		}
 	}
 }
@@ -114,6 +299,27 @@ pub impl RelativeDir {
 			LEFT => 5
 		}
 	}
+
+	fn parse(s : &str) -> Option<RelativeDir> {
+		match s {
+			"FORWARD" => Some(FORWARD),
+			"BACKWARD" => Some(BACKWARD),
+			"RIGHT" => Some(RIGHT),
+			"LEFT" => Some(LEFT),
+			_ => None
+		}
+	}
+}
+
+impl ToStr for RelativeDir {
+	fn to_str(&self) -> ~str {
+		match *self {
+			FORWARD => ~"FORWARD",
+			BACKWARD => ~"BACKWARD",
+			RIGHT => ~"RIGHT",
+			LEFT => ~"LEFT"
+		}
+	}
 }
 
 pub impl Direction {
@@ -195,6 +401,72 @@ pub impl Position {
 		}
 	}
 
+	// Number of steps between the positions
+	fn distance(&self, pos : &Position) -> int {
+		let dx = pos.x - self.x;
+		let dy = pos.y - self.y;
+		if (dx >= 0) == (dy >= 0) {
+			int::max(int::abs(dx), int::abs(dy))
+		} else {
+			int::abs(dx) + int::abs(dy)
+		}
+	}
+
+	// Iterate over every position at most `radius` steps away
+	fn each_within(&self, radius : int, f : &fn(position : &Position)) {
+		for range(-radius, radius + 1) |dx| {
+			for range(max(-radius, dx - radius), min(radius, dx + radius) + 1) |dy| {
+				f(&Position {x: self.x + dx, y: self.y + dy});
+			}
+		}
+	}
+
+	/**
+	 * Hexes on the straight line from here to `to`, both ends included,
+	 * each a neighbour of the one before
+	 */
+	fn line_to(&self, to : &Position) -> ~[Position] {
+		let n = self.distance(to);
+		if n == 0 {
+			return ~[*self];
+		}
+
+		// Cube coordinates (x, y - x, -y) of the points on the line, scaled
+		// by `scale` and nudged so that none falls on the edge between hexes
+		let scale = 1000 * n;
+		let (a0, b0, c0) = (self.x, self.y - self.x, -self.y);
+		let (a1, b1, c1) = (to.x, to.y - to.x, -to.y);
+
+		do vec::from_fn((n + 1) as uint) |i| {
+			let i = i as int;
+			let a = (a0 * n + (a1 - a0) * i) * 1000 + 1;
+			let b = (b0 * n + (b1 - b0) * i) * 1000 + 1;
+			let c = (c0 * n + (c1 - c0) * i) * 1000 - 2;
+			let (ra, rb, rc) = (div_round(a, scale), div_round(b, scale), div_round(c, scale));
+
+			// Rounding may break a + b + c = 0, then trust the two closest
+			let (ea, eb, ec) = (int::abs(ra * scale - a), int::abs(rb * scale - b),
+				int::abs(rc * scale - c));
+			if ea > eb && ea > ec {
+				Position {x: -rb - rc, y: -rc}
+			} else if eb > ec {
+				Position {x: ra, y: -rc}
+			} else {
+				Position {x: ra, y: ra + rb}
+			}
+		}
+	}
+
+	// Direction of a neighbouring position
+	fn direction_to(&self, pos : &Position) -> Option<Direction> {
+		for DIRECTIONS.each |&d| {
+			if self.neighbor(d) == *pos {
+				return Some(d);
+			}
+		}
+		None
+	}
+
 	fn is_neighbor(&self, position : Position) -> bool {
 		let rx = self.x - position.x;
 		let ry = self.y - position.y;
@@ -223,27 +495,53 @@ pub impl Position {
 	}
 }
 
-fn modulo(x :int, m : int) -> int {
+// x mod m, never negative for m > 0
+pub fn modulo(x : int, m : int) -> int {
 	let r = x % m;
 	if r < 0 { r+m } else { r }
 }
 
-static PLAYER_VIEW: int = 10;
+// x / m rounded to the nearest integer, for m > 0
+fn div_round(x : int, m : int) -> int {
+	let n = 2 * x + m;
+	let d = 2 * m;
+	if n < 0 && n % d != 0 { n / d - 1 } else { n / d }
+}
 
 pub impl Creature {
 	fn new<T: MoveController + 'static>(
 			map : @mut Map, position : &Position, direction : Direction,
 			ctr : @T
 			) -> Creature {
+		Creature::with_controller(map, position, direction, ctr as @MoveController)
+	}
+
+	fn with_controller(
+			map : @mut Map, position : &Position, direction : Direction,
+			ctr : @MoveController
+			) -> Creature {
 		Creature {
+			id: map.new_creature_id(),
 			map: map,
 			last_hit_time: 1000,
-			life: 3,
-			controller: ctr as @MoveController,
+			life: combat::DEFAULT_STATS.max_hp,
+			stats: combat::DEFAULT_STATS,
+			name: ~"monster",
+			speed: 100,
+			on_hit: None,
+			behaviour: species::HUNTER,
+			sprite: species::DEFAULT_SPRITE,
+			controller: ctr,
 			pos : *position, dir : direction,
 			action: None, pre_action_ticks: 0, post_action_ticks: 0,
 			map_visible: vec::from_elem(map.width, vec::from_elem(map.height, false)),
 			map_known: vec::from_elem(map.width, vec::from_elem(map.height, false)),
+			level_memory: ~[],
+			inventory: ~[],
+			equipment: vec::from_elem(item::SLOTS.len(), None),
+			statuses: ~[],
+			vision: fov::DEFAULT_VISION,
+			faction: MONSTERS,
 			map_width: map.width,
 			map_height: map.height,
 		}
@@ -253,6 +551,10 @@ pub impl Creature {
 		let mut redraw = false;
 
 		self.last_hit_time += 1;
+		self.tick_statuses();
+		if !self.alive() {
+			return true;
+		}
 
 		if (self.pre_action_ticks > 0) {
 			self.pre_action_ticks -= 1;
@@ -260,11 +562,24 @@ pub impl Creature {
 			match (self.action) {
 				Some(action) => {
 					redraw = true;
+					let action = if self.has_status(status::CONFUSION) {
+						self.stumble(action)
+					} else {
+						action
+					};
 					match (action) {
 						MOVE(d) => self.move(d),
 						TURN(d) => self.turn(d),
-						MELEE(d) => self.melee(d),
-						WAIT => {}
+						MELEE(d) => { self.melee(d); },
+						FIRE(offset) => { self.fire(&offset); },
+						WAIT => {},
+						DESCEND => self.take_stairs(STAIRS_DOWN),
+						ASCEND => self.take_stairs(STAIRS_UP),
+						PICKUP => self.pick_up(),
+						DROP(i) => self.drop(i),
+						EQUIP(i) => self.equip(i),
+						UNEQUIP(slot) => self.unequip(slot),
+						USE(i) => self.use_item(i)
 					}
 					self.action = None
 				}
@@ -272,10 +587,20 @@ pub impl Creature {
 					if (self.post_action_ticks > 0) {
 						self.post_action_ticks -= 1;
 					} else {
-						let action = self.controller.get_move(self);
+						// Stunned creatures aren't asked, so replays stay in step
+						let action = if self.has_status(status::STUN) {
+							WAIT
+						} else {
+							let action = self.controller.get_move(self);
+							match self.map.recorder {
+								Some(recorder) => recorder.record(self.map.ticks, self.id, action),
+								None => {}
+							}
+							action
+						};
 						self.action = Some(action);
-						self.pre_action_ticks = action.pre_ticks();
-						self.post_action_ticks = action.post_ticks();
+						self.pre_action_ticks = self.action_ticks(action.pre_ticks());
+						self.post_action_ticks = self.action_ticks(action.post_ticks());
 					}
 				}
 			}
@@ -283,6 +608,69 @@ pub impl Creature {
 		redraw
 	}
 
+	// Ticks an action lasting `ticks` takes this creature
+	fn action_ticks(&self, ticks : uint) -> uint {
+		let ticks = ticks * self.speed / 100;
+		match (self.has_status(status::HASTE), self.has_status(status::SLOW)) {
+			(true, false) => ticks / 2,
+			(false, true) => ticks * 2,
+			_ => ticks
+		}
+	}
+
+	fn has_status(&self, kind : StatusKind) -> bool {
+		self.statuses.any(|s| s.kind == kind)
+	}
+
+	// Start `kind` for `ticks`, or make it last that long if it's shorter
+	fn add_status(&mut self, kind : StatusKind, ticks : uint) {
+		if ticks == 0 {
+			return;
+		}
+		for self.statuses.each_mut |s| {
+			if s.kind == kind {
+				s.ticks = uint::max(s.ticks, ticks);
+				return;
+			}
+		}
+		self.statuses.push(Status { kind: kind, ticks: ticks });
+	}
+
+	// Count down the statuses, applying poison and regeneration
+	fn tick_statuses(@mut self) {
+		if self.statuses.is_empty() {
+			return;
+		}
+		for self.statuses.each_mut |s| {
+			s.ticks -= 1;
+		}
+		let statuses = copy self.statuses;
+		for statuses.each |s| {
+			if s.ticks % status::PERIOD == 0 {
+				match s.kind {
+					status::POISON => self.take_damage(1),
+					status::REGENERATION => self.heal(1),
+					_ => {}
+				}
+			}
+			if s.ticks == 0 && self.faction == HEROES {
+				self.map.message(fmt!("you are no longer %s", s.kind.adjective()));
+			}
+		}
+		self.statuses = statuses.filtered(|s| s.ticks > 0);
+	}
+
+	// The action a confused creature does instead of `action`
+	fn stumble(@mut self, action : Action) -> Action {
+		let rd = [FORWARD, BACKWARD, LEFT, RIGHT][self.map.rng.gen_uint_range(0, 4)];
+		match action {
+			MOVE(_) => MOVE(rd),
+			TURN(_) => TURN(rd),
+			MELEE(_) => MELEE(rd),
+			_ => action
+		}
+	}
+
 	fn turn(@mut self, rd : RelativeDir) {
 		self.dir.turn_mut(rd);
 	}
@@ -297,21 +685,270 @@ pub impl Creature {
 		}
 	}
 
-	fn melee(@mut self, rd : RelativeDir) {
+	fn melee(@mut self, rd : RelativeDir) -> Option<CombatResult> {
 		let pos = self.pos; // workaround bug
 		let dir = self.dir;
-		let new_position = pos.neighbor(dir.turn(rd));
+		let attack_dir = dir.turn(rd);
+		let new_position = pos.neighbor(attack_dir);
 		match self.map.creature_at(&new_position) {
 			Some(cr) => {
-				cr.hit();
+				let side = combat::Side::of(attack_dir, cr.dir);
+				let result = combat::resolve(self, cr, side, 0, self.map.rng);
+				self.map.message(combat::describe(&result));
+				self.after_hit(&result);
+				Some(result)
 			},
+			None => None
+		}
+	}
+
+	/**
+	 * First creature a shot at `offset` would hit, None if it ends in a
+	 * wall or a door, beyond the edge of the map or out of range
+	 */
+	fn line_of_fire(&mut self, offset : &Position) -> Option<@mut Creature> {
+		let path = self.fire_path(offset);
+		if path.is_empty() {
+			return None;
+		}
+		self.map.creature_at(&path[path.len() - 1])
+	}
+
+	// Hexes a shot at `offset` passes, up to what stops it
+	fn fire_path(&mut self, offset : &Position) -> ~[Position] {
+		let pos = self.pos;
+		let line = pos.line_to(&(pos + *offset));
+		let mut path = ~[];
+		for line.slice(1, line.len()).each |p| {
+			if path.len() as int >= self.stats.range {
+				break;
+			}
+			path.push(*p);
+			let tile = self.map.at(p);
+			if !tile.is_passable() || !tile.can_see_through() || self.map.creature_at(p).is_some() {
+				break;
+			}
+		}
+		path
+	}
+
+	// Let the effect of this creature's hits happen to a defender it hurt
+	fn after_hit(@mut self, result : &CombatResult) {
+		match self.on_hit {
+			Some(effect) if result.damage() > 0 && !result.killed => effect.apply(result.defender),
+			_ => {}
+		}
+	}
+
+	// Shoot at the hex at `offset`, hitting the first creature in the way
+	fn fire(@mut self, offset : &Position) -> Option<CombatResult> {
+		let path = self.fire_path(offset);
+		if path.is_empty() {
+			return None;
+		}
+		self.map.shots.push(copy path);
+
+		let last = path[path.len() - 1];
+		let before = if path.len() > 1 { path[path.len() - 2] } else { self.pos };
+		match self.map.creature_at(&last) {
+			Some(cr) => {
+				let side = match before.direction_to(&last) {
+					Some(d) => combat::Side::of(d, cr.dir),
+					None => combat::FRONT
+				};
+				let distance = path.len() as int;
+				let result = combat::resolve(self, cr, side, combat::range_penalty(distance),
+					self.map.rng);
+				self.map.message(combat::describe(&result));
+				self.after_hit(&result);
+				Some(result)
+			},
+			None => None
+		}
+	}
+
+	fn pick_up(@mut self) {
+		if self.inventory.len() >= item::INVENTORY_SIZE {
+			if self.faction == HEROES {
+				self.map.message(~"you can't carry any more");
+			}
+			return;
+		}
+		let pos = self.pos;
+		match self.map.take_item(&pos) {
+			Some(it) => {
+				self.inventory.push(it);
+				self.map.message(fmt!("%s picked up the %s", combat::name(self), it.name));
+			},
+			None => {}
+		}
+	}
+
+	fn drop(@mut self, i : uint) {
+		if i >= self.inventory.len() {
+			return;
+		}
+		let it = self.inventory.remove(i);
+		let pos = self.pos;
+		self.map.put_item(&pos, it);
+		self.map.message(fmt!("%s dropped the %s", combat::name(self), it.name));
+	}
+
+	// Wear the item at `i` in the inventory, swapping it with what was worn before
+	fn equip(@mut self, i : uint) {
+		if i >= self.inventory.len() {
+			return;
+		}
+		let slot = match self.inventory[i].slot {
+			Some(slot) => slot,
+			None => return
+		};
+		let it = self.inventory.remove(i);
+		match util::replace(&mut self.equipment[slot.to_uint()], Some(it)) {
+			Some(old) => self.inventory.push(old),
+			None => {}
+		}
+		self.map.message(fmt!("%s put on the %s", combat::name(self), it.name));
+	}
+
+	fn unequip(@mut self, slot : Slot) {
+		if self.equipment[slot.to_uint()].is_none() {
+			return;
+		}
+		if self.inventory.len() >= item::INVENTORY_SIZE {
+			if self.faction == HEROES {
+				self.map.message(~"you can't carry any more");
+			}
+			return;
+		}
+		let it = util::replace(&mut self.equipment[slot.to_uint()], None).get();
+		self.inventory.push(it);
+		self.map.message(fmt!("%s took off the %s", combat::name(self), it.name));
+	}
+
+	// Use up the item at `i` in the inventory
+	fn use_item(@mut self, i : uint) {
+		if i >= self.inventory.len() {
+			return;
+		}
+		let effect = match self.inventory[i].effect {
+			Some(effect) => effect,
+			None => return
+		};
+		let it = self.inventory.remove(i);
+		self.map.message(fmt!("%s used the %s", combat::name(self), it.name));
+		effect.apply(self);
+	}
+
+	fn heal(&mut self, n : int) {
+		self.life = int::min(self.stats.max_hp, self.life + n);
+	}
+
+	// Jump to a random free floor tile, if there is one
+	fn blink(@mut self) {
+		match self.map.random_floor_position() {
+			Some(pos) => self.map.move_creature(self, &pos),
 			None => {}
 		}
 	}
 
-	fn hit(@mut self) {
+	// Know every tile of the current level
+	fn reveal_map(&mut self) {
+		for uint::range(0, self.map_width) |x| {
+			for uint::range(0, self.map_height) |y| {
+				self.map_known[x][y] = true;
+			}
+		}
+	}
+
+	// Stats with the bonuses of the equipment
+	fn effective_stats(&self) -> Stats {
+		let mut stats = self.stats;
+		for self.equipment.each |worn| {
+			match *worn {
+				Some(it) => {
+					stats.attack += it.attack;
+					stats.defence += it.defence;
+				},
+				None => {}
+			}
+		}
+		stats
+	}
+
+	// Chance added by the equipment to block attacks from the front, in percent
+	fn block_bonus(&self) -> int {
+		let mut block = 0;
+		for self.equipment.each |worn| {
+			match *worn {
+				Some(it) => block += it.block,
+				None => {}
+			}
+		}
+		block
+	}
+
+	// Vision as narrowed by the equipment
+	fn current_vision(&self) -> VisionProfile {
+		let mut vision = self.vision;
+		for self.equipment.each |worn| {
+			match *worn {
+				Some(it) if it.narrows_vision => vision = vision.narrowed(),
+				_ => {}
+			}
+		}
+		vision
+	}
+
+	// Move to the level the stairs lead to, if standing on `stairs`
+	fn take_stairs(@mut self, stairs : Tile) {
+		let pos = self.pos;
+		if self.map.at(&pos) != stairs {
+			return;
+		}
+		let (target, arrival) = match stairs {
+			STAIRS_DOWN => (self.map.lower, STAIRS_UP),
+			_ => (self.map.upper, STAIRS_DOWN)
+		};
+		let target = match target {
+			Some(level) => level,
+			None => return
+		};
+		let pos = match target.free_position_near(arrival) {
+			Some(pos) => pos,
+			None => return
+		};
+
+		self.map.remove_creature(self);
+		self.enter_level(target, &pos);
+	}
+
+	// Switch to another map, keeping memory of the current one
+	fn enter_level(@mut self, level : @mut Map, pos : &Position) {
+		let old_depth = self.map.depth;
+		let new_depth = level.depth;
+		while self.level_memory.len() <= uint::max(old_depth, new_depth) {
+			self.level_memory.push(None);
+		}
+
+		let known = util::replace(&mut self.map_known, ~[]);
+		self.level_memory[old_depth] = Some(known);
+		self.map_known = match util::replace(&mut self.level_memory[new_depth], None) {
+			Some(known) => known,
+			None => vec::from_elem(level.width, vec::from_elem(level.height, false))
+		};
+
+		self.map = level;
+		self.map_width = level.width;
+		self.map_height = level.height;
+		self.map_visible = vec::from_elem(level.width, vec::from_elem(level.height, false));
+		self.pos = *pos;
+		level.place_creature(self);
+	}
+
+	fn take_damage(@mut self, damage : int) {
 		self.last_hit_time = 0;
-		self.life -= 1;
+		self.life -= damage;
 
 		if (self.life <= 0) {
 			self.die();
@@ -326,91 +963,191 @@ pub impl Creature {
 		self.life > 0
 	}
 
-	fn mark_visible(&mut self, pos : &Position) {
-		let p = self.map.wrap_position(pos);
+	// Turn into a member of `species`, at full health
+	fn set_species(&mut self, species : &Species) {
+		self.name = copy species.name;
+		self.set_stats(species.stats);
+		self.speed = species.speed;
+		self.vision = species.vision;
+		self.on_hit = species.on_hit;
+		self.behaviour = species.behaviour;
+		self.sprite = species.sprite;
+	}
 
-		self.map_visible[p.x][p.y] = true;
+	// Replace the stats, healing up to the new maximum
+	fn set_stats(&mut self, stats : Stats) {
+		self.stats = stats;
+		self.life = stats.max_hp;
 	}
 
-	fn mark_known(&mut self, pos : &Position) {
-		let p = self.map.wrap_position(pos);
+	// Other creatures in view
+	fn visible_creatures(&self) -> ~[@mut Creature] {
+		let map = self.map;
+		let mut seen = ~[];
+		do self.pos.each_within(self.current_vision().radius as int) |p| {
+			if self.sees(p) {
+				match map.creature_at(p) {
+					Some(other) if other.id != self.id && !seen.any(|c| c.id == other.id) => {
+						seen.push(other);
+					},
+					_ => {}
+				}
+			}
+		}
+		seen
+	}
 
-		self.map_known[p.x][p.y] = true;
+	fn is_hostile(&self, other : @mut Creature) -> bool {
+		self.faction != other.faction
 	}
 
-	fn sees(&self, pos: &Position) -> bool {
-		let p = self.map.wrap_position(pos);
+	// Move forward if facing `dir`, otherwise turn towards it
+	fn action_towards(&self, dir : Direction) -> Action {
+		match dir.relative_to(self.dir).to_uint() {
+			0 => MOVE(FORWARD),
+			1 | 2 | 3 => TURN(RIGHT),
+			_ => TURN(LEFT)
+		}
+	}
 
-		self.map_visible[p.x][p.y]
+	fn mark_visible(&mut self, pos : &Position) {
+		match self.map.normalize(pos) {
+			Some(p) => self.map_visible[p.x][p.y] = true,
+			None => {}
+		}
 	}
 
-	fn knows(&self, pos: &Position) -> bool {
-		let p = self.map.wrap_position(pos);
+	fn mark_known(&mut self, pos : &Position) {
+		match self.map.normalize(pos) {
+			Some(p) => self.map_known[p.x][p.y] = true,
+			None => {}
+		}
+	}
 
-		self.map_known[p.x][p.y]
+	fn sees(&self, pos: &Position) -> bool {
+		match self.map.normalize(pos) {
+			Some(p) => self.map_visible[p.x][p.y],
+			None => false
+		}
+	}
+
+	fn knows(&self, pos: &Position) -> bool {
+		match self.map.normalize(pos) {
+			Some(p) => self.map_known[p.x][p.y],
+			None => false
+		}
 	}
 
 	fn position(&self) -> Position {
 		self.pos
 	}
 
-	// Iterate over a rectangle in front of the Creature
+	// Iterate over a rectangle in front of the Creature, holding all it can see
 	fn each_in_view_rect(&self, f : &fn(position : &Position)) {
-		Position{x:0,y:0}.each_around(PLAYER_VIEW, 2, PLAYER_VIEW, PLAYER_VIEW, f)
+		let vision = self.current_vision();
+		let radius = vision.radius as int;
+		// Only a full circle of vision reaches far behind
+		let behind = if vision.cone >= 360 { radius } else { 2 };
+		Position{x:0,y:0}.each_around(radius, behind, radius, radius, f)
 	}
 
-	// Very hacky, recursive LoS algorithm
-	fn do_view(&mut self, pos: &Position,
-		main_dir : Direction, dir : Option<Direction>, pdir : Option<Direction>, depth: uint) {
-		if (depth == 0) {
-			return;
+	fn update_visibility(&mut self) {
+		self.map_visible = vec::from_elem(self.map.width, vec::from_elem(self.map.height, false));
+
+		let map = self.map;
+		let position = copy self.pos;
+		let vision = self.current_vision();
+		let radius = if self.has_status(status::BLIND) { 0 } else { vision.radius_in(map.dark) };
+
+		do fov::compute_cone(&position, radius, self.dir, vision.cone,
+				|p| !map.at(p).can_see_through()) |p| {
+			self.mark_visible(p);
+			self.mark_known(p);
 		}
+	}
+}
 
-		self.mark_visible(pos);
-		self.mark_known(pos);
+pub impl Topology {
+	fn parse(s : &str) -> Option<Topology> {
+		match s {
+			"torus" => Some(TORUS),
+			"bounded" => Some(BOUNDED),
+			_ => None
+		}
+	}
 
-		let neighbors = match (dir, pdir) {
-			(Some(dir), Some(pdir)) => {
-				if dir == pdir {
-					~[dir]
-				} else {
-					~[dir, pdir]
-				}
-			},
-			(Some(dir), None) => {
-				if main_dir == dir {
-					~[dir, dir.turn(LEFT), dir.turn(RIGHT)]
+	// Position inside a map of the given size for `pos`, None if it's beyond a bounded map's edge
+	fn wrap(&self, pos : &Position, width : uint, height : uint) -> Option<Position> {
+		match *self {
+			TORUS => Some(Position {
+				x: modulo(pos.x, width as int),
+				y: modulo(pos.y, height as int)
+			}),
+			BOUNDED => {
+				if pos.x >= 0 && pos.x < width as int && pos.y >= 0 && pos.y < height as int {
+					Some(*pos)
 				} else {
-					~[dir, main_dir]
+					None
 				}
-			},
-			_ => {
-				~[main_dir, main_dir.turn(LEFT), main_dir.turn(RIGHT)]
 			}
-		};
+		}
+	}
 
-		if self.map.at(pos).can_see_through() {
-			for neighbors.each |&d| {
-				let n = pos.neighbor(d);
-				match dir {
-					Some(_) => {
-						self.do_view(&n, d, Some(d), dir, depth - 1);
-					},
-					None => {
-						self.do_view(&n, main_dir, Some(d), dir, depth - 1);
+	// Number of steps between the positions on a map of the given size
+	fn distance(&self, width : uint, height : uint, a : &Position, b : &Position) -> int {
+		let offset = self.offset(width, height, a, b);
+		a.distance(&(*a + offset))
+	}
+
+	// Shortest b - a, looking across the edges of a torus
+	fn offset(&self, width : uint, height : uint, a : &Position, b : &Position) -> Position {
+		match *self {
+			BOUNDED => *b - *a,
+			TORUS => {
+				let w = width as int;
+				let h = height as int;
+				let mut best = *b - *a;
+				let mut best_dist = int::max_value;
+				for [-1, 0, 1].each |&i| {
+					for [-1, 0, 1].each |&j| {
+						let p = Position {x: b.x + i * w, y: b.y + j * h};
+						if a.distance(&p) < best_dist {
+							best = p - *a;
+							best_dist = a.distance(&p);
+						}
 					}
-				};
+				}
+				best
 			}
 		}
 	}
+}
 
-	fn update_visibility(&mut self) {
-		self.map_visible = vec::from_elem(self.map.width, vec::from_elem(self.map.height, false));
+impl ToStr for Topology {
+	fn to_str(&self) -> ~str {
+		match *self {
+			TORUS => ~"torus",
+			BOUNDED => ~"bounded"
+		}
+	}
+}
 
-		let position = copy self.pos;
-		let direction = copy self.dir;
+pub impl Faction {
+	fn parse(s : &str) -> Option<Faction> {
+		match s {
+			"heroes" => Some(HEROES),
+			"monsters" => Some(MONSTERS),
+			_ => None
+		}
+	}
+}
 
-		self.do_view(&position, direction, None, None, PLAYER_VIEW as uint);
+impl ToStr for Faction {
+	fn to_str(&self) -> ~str {
+		match *self {
+			HEROES => ~"heroes",
+			MONSTERS => ~"monsters"
+		}
 	}
 }
 
@@ -429,9 +1166,23 @@ pub impl Tile {
 		}
 	}
 
+	fn is_stairs(&self) -> bool {
+		match *self {
+			STAIRS_DOWN | STAIRS_UP => true,
+			_ => false
+		}
+	}
+
+	fn is_door(&self) -> bool {
+		match *self {
+			DOOR => true,
+			_ => false
+		}
+	}
+
 	fn can_see_through(&self) -> bool {
 		match *self {
-			WALL => false,
+			WALL | DOOR => false,
 			_ => true
 		}
 	}
@@ -442,20 +1193,50 @@ pub impl Tile {
 			_ => true
 		}
 	}
+
+	// Character of the tile in save and map files
+	fn to_char(&self) -> char {
+		match *self {
+			FLOOR => '.',
+			WALL => '#',
+			DOOR => '+',
+			STAIRS_DOWN => '>',
+			STAIRS_UP => '<'
+		}
+	}
+
+	// Inverse of `to_char`
+	fn from_char(c : char) -> Option<Tile> {
+		match c {
+			'.' => Some(FLOOR),
+			'#' => Some(WALL),
+			'+' => Some(DOOR),
+			'>' => Some(STAIRS_DOWN),
+			'<' => Some(STAIRS_UP),
+			_ => None
+		}
+	}
 }
 
 impl MapView for Map {
 	fn at(&mut self, pos: &Position) -> Tile {
-		let p = self.wrap_position(pos);
-		self.tiles[p.x][p.y]
+		match self.normalize(pos) {
+			Some(p) => self.tiles[p.x][p.y],
+			None => WALL
+		}
 	}
 	fn creature_at(&mut self, pos: &Position) -> Option<@mut Creature> {
-		let p = self.wrap_position(pos);
-		self.creatures[p.x][p.y]
+		match self.normalize(pos) {
+			Some(p) => self.creatures[p.x][p.y],
+			None => None
+		}
 	}
 	fn translate(&self, pos : &Position) -> Position {
 		*pos
 	}
+	fn canonical(&self, pos : &Position) -> Option<Position> {
+		self.normalize(pos)
+	}
 }
 
 fn each_in_vrect<T: MapView>(s: &mut T, cp : &Position, rx : int, ry : int, f : &fn(position : Position, t: Tile)) {
@@ -470,28 +1251,153 @@ fn each_in_vrect<T: MapView>(s: &mut T, cp : &Position, rx : int, ry : int, f :
 }
 
 pub impl Map {
-	fn new() -> Map {
-		let rng = rand::Rng();
+	fn generate(width : uint, height : uint, topology : Topology,
+			generator : &mut MapGenerator, rng : @mut GameRng) -> Map {
+		let map = generator.generate(width, height, topology, rng);
 
-		let map = vec::from_fn(MAP_WIDTH, |_| {
-			vec::from_fn(MAP_HEIGHT, |_| {
-				if (rng.gen_int_range(0, 3) == 0) {
-					WALL
-				} else {
-					FLOOR
-				}
+		Map::from_tiles(map, topology, rng)
+	}
+
+	// Map without creatures, `tiles` indexed by [x][y]
+	fn from_tiles(tiles : ~[~[Tile]], topology : Topology, rng : @mut GameRng) -> Map {
+		let width = tiles.len();
+		let height = tiles[0].len();
+
+		let creatures = vec::from_fn(width, |_| {
+			vec::from_fn(height, |_| {
+				None
 			})
 		});
 
-		let creatures = vec::from_fn(MAP_WIDTH, |_| {
-			vec::from_fn(MAP_HEIGHT, |_| {
-				None
+		let items = vec::from_fn(width, |_| {
+			vec::from_fn(height, |_| {
+				~[]
 			})
 		});
 
 		Map {
-			tiles: map, creatures: creatures,
-			width: MAP_WIDTH, height: MAP_HEIGHT
+			tiles: tiles, creatures: creatures, items: items,
+			width: width, height: height,
+			topology: topology,
+			rng: rng,
+			ticks: 0,
+			next_creature_id: @mut 0,
+			recorder: None,
+			log: @mut MessageLog::new(),
+			depth: 0,
+			dark: false,
+			shots: ~[],
+			flows: ~[],
+			upper: None,
+			lower: None
+		}
+	}
+
+	fn message(&mut self, text : ~str) {
+		self.log.add(self.ticks, text);
+	}
+
+	fn new_creature_id(&mut self) -> uint {
+		let id = *self.next_creature_id;
+		*self.next_creature_id += 1;
+		id
+	}
+
+	fn find_tile(&self, tile : Tile) -> Option<Position> {
+		for range(0, self.width as int) |x| {
+			for range(0, self.height as int) |y| {
+				if self.tiles[x][y] == tile {
+					return Some(Position {x: x, y: y});
+				}
+			}
+		}
+		None
+	}
+
+	// Position of `tile`, or next to it if occupied
+	fn free_position_near(&mut self, tile : Tile) -> Option<Position> {
+		let pos = match self.find_tile(tile) {
+			Some(pos) => pos,
+			None => return None
+		};
+		if self.creature_at(&pos).is_none() {
+			return Some(pos);
+		}
+		for DIRECTIONS.each |&d| {
+			let n = pos.neighbor(d);
+			if self.at(&n).is_passable() && self.creature_at(&n).is_none() {
+				return self.normalize(&n);
+			}
+		}
+		None
+	}
+
+	// Item on top of the stack at `pos`
+	fn top_item(&self, pos : &Position) -> Option<Item> {
+		match self.normalize(pos) {
+			Some(p) if !self.items[p.x][p.y].is_empty() => Some(*self.items[p.x][p.y].last()),
+			_ => None
+		}
+	}
+
+	// Take the top item at `pos` off the map
+	fn take_item(&mut self, pos : &Position) -> Option<Item> {
+		match self.normalize(pos) {
+			Some(p) if !self.items[p.x][p.y].is_empty() => Some(self.items[p.x][p.y].pop()),
+			_ => None
+		}
+	}
+
+	fn put_item(&mut self, pos : &Position, it : Item) {
+		match self.normalize(pos) {
+			Some(p) => self.items[p.x][p.y].push(it),
+			None => {}
+		}
+	}
+
+	// Leave `n` random items on random floor tiles
+	fn scatter_items(&mut self, n : uint) {
+		for uint::range(0, n) |_| {
+			let pos = match self.random_floor_position() {
+				Some(pos) => pos,
+				None => return
+			};
+			let it = Item::random(self.rng);
+			self.put_item(&pos, it);
+		}
+	}
+
+	// Random floor tile nobody stands on, None if there is none
+	fn random_floor_position(&mut self) -> Option<Position> {
+		self.random_floor_position_where(|_| true)
+	}
+
+	// Random floor tile nobody stands on from which `tile` can be walked to,
+	// any free floor tile if the level has no `tile`
+	fn random_floor_position_reaching(&mut self, tile : Tile) -> Option<Position> {
+		let goal = match self.find_tile(tile) {
+			Some(goal) => goal,
+			None => return self.random_floor_position()
+		};
+		let flow = DijkstraMap::new(&*self, [goal]);
+		self.random_floor_position_where(|p| flow.values[p.x][p.y] != dijkstra::UNREACHED)
+	}
+
+	// Random floor tile nobody stands on for which `f` is true
+	fn random_floor_position_where(&mut self, f : &fn(&Position) -> bool) -> Option<Position> {
+		let mut free = ~[];
+		for range(0, self.width as int) |x| {
+			for range(0, self.height as int) |y| {
+				let pos = Position {x: x, y: y};
+				if self.tiles[x][y].is_floor() && self.creatures[x][y].is_none() && f(&pos) {
+					free.push(pos);
+				}
+			}
+		}
+		if free.is_empty() {
+			None
+		} else {
+			Some(free[self.rng.gen_uint_range(0, free.len())])
 		}
 	}
 
@@ -502,6 +1408,26 @@ pub impl Map {
 		}
 	}
 
+	fn contains(&self, pos : &Position) -> bool {
+		pos.x >= 0 && pos.x < self.width as int &&
+		pos.y >= 0 && pos.y < self.height as int
+	}
+
+	// Position inside the map for `pos`, None if it's beyond a bounded map's edge
+	fn normalize(&self, pos : &Position) -> Option<Position> {
+		self.topology.wrap(pos, self.width, self.height)
+	}
+
+	// Number of steps between the positions, the short way around a torus
+	fn distance(&self, a : &Position, b : &Position) -> int {
+		self.topology.distance(self.width, self.height, a, b)
+	}
+
+	// Shortest step from `a` to `b`, the short way around a torus
+	fn offset(&self, a : &Position, b : &Position) -> Position {
+		self.topology.offset(self.width, self.height, a, b)
+	}
+
 	fn for_each_tile(&mut self, f : &fn(Position, &mut Tile)) {
 		for range(0, self.width as int) |x| {
 			for range(0, self.height as int) |y| {
@@ -537,25 +1463,52 @@ pub impl Map {
 		}
 	}
 
+	// Creature on a random free floor tile, None if the level has none.
+	// Not on doors or stairs, where map files can't show creatures.
 	fn spawn_random_creature<T:MoveController + 'static>(
 			@mut self, controller : @T
-			) -> @mut Creature {
-		let rng = rand::Rng();
-		let pos = &Position{
-			x: rng.gen_int_range(0, self.width as int),
-			y: rng.gen_int_range(0, self.height as int)
-		};
+			) -> Option<@mut Creature> {
+		match self.random_floor_position() {
+			Some(pos) => {
+				let dir = N.turn_by_int(self.rng.gen_int_range(0, 6));
+				self.spawn_creature(&pos, dir, controller)
+			},
+			None => None
+		}
+	}
 
-		let dir = N.turn_by_int(rng.gen_int_range(0, 6));
+	// Monster of a species from the spawn table of this level, on a random tile
+	fn spawn_random_species<T:MoveController + 'static>(
+			@mut self, registry : &SpeciesRegistry, controller : @T
+			) -> Option<@mut Creature> {
+		let species = registry.pick(self.depth, self.rng);
+		match self.spawn_random_creature(controller) {
+			Some(creature) => {
+				creature.set_species(species);
+				Some(creature)
+			},
+			None => None
+		}
+	}
 
-		match (self.spawn_creature(pos, dir, controller)) {
-			None => self.spawn_random_creature(controller),
-			Some(creature) => creature
+	// Put an already constructed creature on the map
+	fn place_creature(&mut self, cr : @mut Creature) -> bool {
+		let pos = self.wrap_position(&cr.pos);
+		match (self.creatures[pos.x][pos.y]) {
+			Some(_) => false,
+			None => {
+				cr.pos = pos;
+				self.creatures[pos.x][pos.y] = Some(cr);
+				true
+			}
 		}
 	}
 
 	fn move_creature(&mut self, cr : @mut Creature, pos : &Position) {
-		let pos = &self.wrap_position(pos);
+		let pos = &match self.normalize(pos) {
+			Some(pos) => pos,
+			None => return
+		};
 		match (self.creatures[pos.x][pos.y]) {
 			Some(_) => {},
 			None => {
@@ -570,6 +1523,7 @@ pub impl Map {
 		let pos = cr.pos;
 		let pos = self.wrap_position(&pos);
 		self.creatures[pos.x][pos.y] = None;
+		dijkstra::forget_creature(self, cr.id);
 	}
 }
 
@@ -582,6 +1536,19 @@ pub impl<'self> RelativeMap<'self> {
 	fn base(&self) -> &'self mut Map {
 		&mut *self.map
 	}
+
+	// Inverse of `translate`
+	fn untranslate(&self, pos : &Position) -> Position {
+		let d = *pos - self.pos;
+		match self.dir {
+			N => d,
+			S => Position { x: -d.x, y: -d.y },
+			NW => Position { x: d.x - d.y, y: d.x },
+			SE => Position { x: d.y - d.x, y: -d.x },
+			NE => Position { x: d.y, y: d.y - d.x },
+			SW => Position { x: -d.y, y: d.x - d.y }
+		}
+	}
 }
 
 impl MapView for RelativeMap<'self> {
@@ -595,6 +1562,11 @@ impl MapView for RelativeMap<'self> {
 		self.map.creature_at(&pos)
 	}
 
+	fn canonical(&self, pos : &Position) -> Option<Position> {
+		let pos = self.translate(pos);
+		self.map.normalize(&pos)
+	}
+
 	fn translate(&self, pos : &Position) -> Position {
 		match self.dir {
 			N => Position {
@@ -0,0 +1,193 @@
+use core::io;
+use core::io::{Writer, WriterUtil};
+use core::managed;
+use core::str;
+use core::vec;
+use core::to_str::ToStr;
+
+use map;
+use map::{Map, Creature, Position, Tile};
+
+/*
+ * Hand-authored maps
+ *
+ * Each line of the file is a row of the map as seen on the screen and
+ * each character a tile:
+ *
+ *     .  floor
+ *     #  wall
+ *     +  door
+ *     >  stairs down
+ *     <  stairs up
+ *     @  floor where the player can start
+ *     m  floor with a monster
+ *        (space) outside of the map, filled with wall
+ *
+ * Lines starting with ';' are comments. As hex columns are shifted by
+ * half a tile, the tile in column x of row r is at Position {x: x,
+ * y: r + x / 2}, moved up so the topmost tile has y = 0. The map is
+ * as high as needed to hold all the rows without wrapping around.
+ */
+
+/**
+ * Map read from a file, with the places for creatures
+ */
+pub struct MapFile {
+	tiles : ~[~[Tile]],
+	spawns : ~[Position],
+	monsters : ~[Position]
+}
+
+pub struct ParseError {
+	line : uint,
+	col : uint,
+	msg : ~str
+}
+
+impl ToStr for ParseError {
+	fn to_str(&self) -> ~str {
+		fmt!("line %u, column %u: %s", self.line, self.col, self.msg)
+	}
+}
+
+fn error<T>(line : uint, col : uint, msg : ~str) -> Result<T, ParseError> {
+	Err(ParseError { line: line, col: col, msg: msg })
+}
+
+fn screen_to_y(x : uint, r : uint) -> int {
+	(r + (x >> 1)) as int
+}
+
+pub fn parse(text : &str) -> Result<MapFile, ParseError> {
+	let mut rows : ~[(uint, ~str)] = ~[];
+
+	let mut line = 0;
+	for str::each_line(text) |l| {
+		line += 1;
+		if l.starts_with(";") || l.trim().is_empty() {
+			loop;
+		}
+		rows.push((line, l.trim_right().to_owned()));
+	}
+
+	if rows.is_empty() {
+		return error(line, 1, ~"no map rows");
+	}
+
+	// Extent of the map
+	let mut width = 0;
+	let mut y_min = int::max_value;
+	let mut y_max = int::min_value;
+	for rows.eachi |r, &(_, ref row)| {
+		width = uint::max(width, row.len());
+		for uint::range(0, row.len()) |x| {
+			if row.char_at(x) != ' ' {
+				y_min = int::min(y_min, screen_to_y(x, r));
+				y_max = int::max(y_max, screen_to_y(x, r));
+			}
+		}
+	}
+	let height = (y_max - y_min + 1) as uint;
+
+	let mut tiles = vec::from_fn(width, |_| vec::from_elem(height, map::WALL));
+	let mut spawns = ~[];
+	let mut monsters = ~[];
+	let mut floor = 0;
+
+	for rows.eachi |r, &(line, ref row)| {
+		for uint::range(0, row.len()) |x| {
+			let c = row.char_at(x);
+			if c == ' ' {
+				loop;
+			}
+
+			let pos = Position {x: x as int, y: screen_to_y(x, r) - y_min};
+			let tile = match c {
+				'@' => {
+					spawns.push(pos);
+					map::FLOOR
+				},
+				'm' => {
+					monsters.push(pos);
+					map::FLOOR
+				},
+				c => match Tile::from_char(c) {
+					Some(tile) => tile,
+					None => return error(line, x + 1, fmt!("unknown tile '%c'", c))
+				}
+			};
+			if tile.is_passable() {
+				floor += 1;
+			}
+			tiles[pos.x][pos.y] = tile;
+		}
+	}
+
+	if floor == 0 {
+		return error(line, 1, ~"map has no floor");
+	}
+
+	Ok(MapFile { tiles: tiles, spawns: spawns, monsters: monsters })
+}
+
+pub fn load(path : &Path) -> Result<MapFile, ~str> {
+	match io::read_whole_file_str(path) {
+		Ok(text) => {
+			match parse(text) {
+				Ok(file) => Ok(file),
+				Err(e) => Err(fmt!("%s: %s", path.to_str(), e.to_str()))
+			}
+		},
+		Err(e) => Err(e)
+	}
+}
+
+/**
+ * Text of `map` in the format read by `parse`. Creatures are written as
+ * monsters, except for `player`. The format only has creatures on floor,
+ * so a creature standing anywhere else is an error.
+ */
+pub fn to_text(map : &mut Map, player : Option<@mut Creature>) -> Result<~str, ~str> {
+	let mut text = ~"";
+
+	// The last column starts this many rows above the first one
+	let offset = ((map.width - 1) >> 1) as int;
+
+	for int::range(-offset, map.height as int) |r| {
+		let mut row = ~"";
+		for uint::range(0, map.width) |x| {
+			let y = r + (x >> 1) as int;
+			let c = if y < 0 || y >= map.height as int {
+				' '
+			} else {
+				match (map.creatures[x][y], player) {
+					(Some(_), _) if !map.tiles[x][y].is_floor() => {
+						return Err(fmt!("can't write the creature on '%c' at %u,%d",
+							map.tiles[x][y].to_char(), x, y));
+					},
+					(Some(cr), Some(p)) if managed::mut_ptr_eq(cr, p) => '@',
+					(Some(_), _) => 'm',
+					(None, _) => map.tiles[x][y].to_char()
+				}
+			};
+			str::push_char(&mut row, c);
+		}
+		text += row.trim_right() + "\n";
+	}
+	Ok(text)
+}
+
+pub fn export(path : &Path, map : &mut Map, player : Option<@mut Creature>) -> Result<(), ~str> {
+	let text = match to_text(map, player) {
+		Ok(text) => text,
+		Err(e) => return Err(e)
+	};
+	match io::file_writer(path, [io::Create, io::Truncate]) {
+		Ok(out) => {
+			out.write_str(text);
+			out.flush();
+			Ok(())
+		},
+		Err(e) => Err(e)
+	}
+}
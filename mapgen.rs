@@ -0,0 +1,373 @@
+use core::vec;
+
+use map;
+use map::{Position, Tile, Topology, DIRECTIONS, FLOOR, WALL, DOOR};
+use rng::GameRng;
+
+/**
+ * Source of the tiles of a new map, indexed by [x][y]
+ */
+pub trait MapGenerator {
+	fn generate(&mut self, width : uint, height : uint, topology : Topology,
+			rng : @mut GameRng) -> ~[~[Tile]];
+}
+
+// Generator by the name used on the command line
+pub fn generator(name : &str) -> Option<@mut MapGenerator> {
+	match name {
+		"random" => Some(@mut RandomGenerator::new() as @mut MapGenerator),
+		"caves" => Some(@mut CaveGenerator::new() as @mut MapGenerator),
+		"rooms" => Some(@mut RoomsGenerator::new() as @mut MapGenerator),
+		_ => None
+	}
+}
+
+/**
+ * Walls scattered uniformly at random
+ */
+pub struct RandomGenerator {
+	// Percentage of walls
+	wall_percent : int
+}
+
+pub impl RandomGenerator {
+	fn new() -> RandomGenerator {
+		RandomGenerator { wall_percent: 33 }
+	}
+}
+
+impl MapGenerator for RandomGenerator {
+	fn generate(&mut self, width : uint, height : uint, _ : Topology,
+			rng : @mut GameRng) -> ~[~[Tile]] {
+		do vec::from_fn(width) |_| {
+			do vec::from_fn(height) |_| {
+				if rng.gen_int_range(0, 100) < self.wall_percent {
+					WALL
+				} else {
+					FLOOR
+				}
+			}
+		}
+	}
+}
+
+/**
+ * Fill every floor tile that is not reachable from the largest floor
+ * region with walls. Returns size of the remaining region.
+ */
+pub fn keep_largest_region(tiles : &mut ~[~[Tile]], topology : Topology) -> uint {
+	let width = tiles.len();
+	let height = tiles[0].len();
+
+	// region number of each tile, 0 for walls and not yet visited floor
+	let mut region = vec::from_elem(width, vec::from_elem(height, 0u));
+	let mut sizes = ~[0u];
+
+	for uint::range(0, width) |x| {
+		for uint::range(0, height) |y| {
+			if !tiles[x][y].is_floor() || region[x][y] != 0 {
+				loop;
+			}
+
+			let id = sizes.len();
+			let mut size = 0;
+			let mut stack = ~[Position {x: x as int, y: y as int}];
+			region[x][y] = id;
+
+			while !stack.is_empty() {
+				let pos = stack.pop();
+				size += 1;
+
+				for DIRECTIONS.each |&d| {
+					match topology.wrap(&pos.neighbor(d), width, height) {
+						Some(n) => {
+							if tiles[n.x][n.y].is_floor() && region[n.x][n.y] == 0 {
+								region[n.x][n.y] = id;
+								stack.push(n);
+							}
+						},
+						None => {}
+					}
+				}
+			}
+			sizes.push(size);
+		}
+	}
+
+	let mut largest = 0;
+	for uint::range(1, sizes.len()) |id| {
+		if sizes[id] > sizes[largest] {
+			largest = id;
+		}
+	}
+
+	for uint::range(0, width) |x| {
+		for uint::range(0, height) |y| {
+			if tiles[x][y].is_floor() && region[x][y] != largest {
+				tiles[x][y] = WALL;
+			}
+		}
+	}
+	sizes[largest]
+}
+
+/**
+ * Cave generator
+ *
+ * Starts with randomly placed walls and smooths them with a cellular
+ * automaton over the six hex neighbours: a tile becomes a wall when
+ * most of its neighbours are walls and floor when few are. Only the
+ * largest connected floor region is kept.
+ */
+pub struct CaveGenerator {
+	// Percentage of walls in the initial random fill
+	wall_percent : int,
+	smoothing_steps : uint,
+	// Neighbouring walls needed to turn a tile into a wall
+	wall_limit : uint,
+	// Neighbouring walls at or below which a tile turns into floor
+	floor_limit : uint,
+	// Smallest acceptable part of the map covered by floor, in percent
+	min_floor_percent : uint
+}
+
+pub impl CaveGenerator {
+	fn new() -> CaveGenerator {
+		CaveGenerator {
+			wall_percent: 45,
+			smoothing_steps: 4,
+			wall_limit: 4,
+			floor_limit: 2,
+			min_floor_percent: 35
+		}
+	}
+
+	fn smooth(&self, tiles : &~[~[Tile]], topology : Topology) -> ~[~[Tile]] {
+		let width = tiles.len();
+		let height = tiles[0].len();
+
+		do vec::from_fn(width) |x| {
+			do vec::from_fn(height) |y| {
+				let pos = Position {x: x as int, y: y as int};
+				let mut walls = 0;
+				for DIRECTIONS.each |&d| {
+					match topology.wrap(&pos.neighbor(d), width, height) {
+						Some(n) => if tiles[n.x][n.y].is_wall() { walls += 1; },
+						// The edge of a bounded map counts as wall
+						None => walls += 1
+					}
+				}
+
+				if walls >= self.wall_limit {
+					WALL
+				} else if walls <= self.floor_limit {
+					FLOOR
+				} else {
+					tiles[x][y]
+				}
+			}
+		}
+	}
+
+}
+
+impl MapGenerator for CaveGenerator {
+	fn generate(&mut self, width : uint, height : uint, topology : Topology,
+			rng : @mut GameRng) -> ~[~[Tile]] {
+		loop {
+			let mut tiles = do vec::from_fn(width) |_| {
+				do vec::from_fn(height) |_| {
+					if rng.gen_int_range(0, 100) < self.wall_percent {
+						WALL
+					} else {
+						FLOOR
+					}
+				}
+			};
+
+			for uint::range(0, self.smoothing_steps) |_| {
+				tiles = self.smooth(&tiles, topology);
+			}
+
+			let floor = keep_largest_region(&mut tiles, topology);
+			if floor * 100 >= width * height * self.min_floor_percent {
+				return tiles;
+			}
+		}
+	}
+}
+
+/**
+ * Room of a dungeon. Rectangular on screen, so `r` is the screen row:
+ * a tile in column x and row r is at Position {x: x, y: r + x / 2}.
+ */
+struct Room {
+	x : int,
+	r : int,
+	w : int,
+	h : int
+}
+
+pub impl Room {
+	fn each_tile(&self, f : &fn(Position)) {
+		for int::range(self.x, self.x + self.w) |x| {
+			for int::range(self.r, self.r + self.h) |r| {
+				f(Position {x: x, y: r + (x >> 1)});
+			}
+		}
+	}
+
+	fn center(&self) -> Position {
+		let x = self.x + self.w / 2;
+		Position {x: x, y: self.r + self.h / 2 + (x >> 1)}
+	}
+
+	// Do the rooms overlap when grown by `margin` tiles
+	fn overlaps(&self, room : &Room, margin : int) -> bool {
+		self.x - margin < room.x + room.w && room.x - margin < self.x + self.w &&
+		self.r - margin < room.r + room.h && room.r - margin < self.r + self.h
+	}
+}
+
+// Smallest width and height at which two rooms fit most of the time
+pub static ROOMS_MIN_SIZE : uint = 12;
+
+/**
+ * Rooms and corridors generator
+ *
+ * Places non-overlapping rooms and connects each of them to the nearest
+ * already connected room with a corridor. Corridors entering a room
+ * sometimes get a door. Maps too small for two rooms are caves instead.
+ */
+pub struct RoomsGenerator {
+	max_rooms : uint,
+	min_room_size : int,
+	max_room_size : int,
+	// Chance of a door where a corridor enters a room, in percent
+	door_percent : int,
+	// Tries at fitting two rooms before falling back to caves
+	max_attempts : uint
+}
+
+pub impl RoomsGenerator {
+	fn new() -> RoomsGenerator {
+		RoomsGenerator {
+			max_rooms: 12,
+			min_room_size: 3,
+			max_room_size: 7,
+			door_percent: 50,
+			max_attempts: 100
+		}
+	}
+
+	fn place_room(&self, width : int, height : int, rng : @mut GameRng) -> Option<Room> {
+		let w = rng.gen_int_range(self.min_room_size, self.max_room_size + 1);
+		let h = rng.gen_int_range(self.min_room_size, self.max_room_size + 1);
+		if w + 2 >= width {
+			return None;
+		}
+		let x = rng.gen_int_range(1, width - w - 1);
+
+		// Keep every y = r + x / 2 of the room in [1, height - 2]
+		let r_min = 1 - (x >> 1);
+		let r_max = height - h - ((x + w - 1) >> 1);
+		if r_min >= r_max {
+			return None;
+		}
+		Some(Room {x: x, r: rng.gen_int_range(r_min, r_max), w: w, h: h})
+	}
+
+	fn door(&self, rng : @mut GameRng) -> bool {
+		rng.gen_int_range(0, 100) < self.door_percent
+	}
+
+	fn carve_corridor(&self, tiles : &mut ~[~[Tile]], in_room : &~[~[bool]],
+			from : Position, to : Position, rng : @mut GameRng) {
+		let mut pos = from;
+		let mut last_dir = map::N;
+
+		while pos != to {
+			let dist = pos.distance(&to);
+			let closer = do DIRECTIONS.filtered |&d| {
+				pos.neighbor(d).distance(&to) < dist
+			};
+
+			// Prefer going straight to keep corridors tidy
+			let dir = if closer.contains(&last_dir) && rng.gen_int_range(0, 4) != 0 {
+				last_dir
+			} else {
+				closer[rng.gen_uint_range(0, closer.len())]
+			};
+
+			let next = pos.neighbor(dir);
+			let leaving = in_room[pos.x][pos.y] && !in_room[next.x][next.y];
+			let entering = !in_room[pos.x][pos.y] && in_room[next.x][next.y];
+
+			if tiles[next.x][next.y].is_wall() {
+				tiles[next.x][next.y] = if leaving && self.door(rng) { DOOR } else { FLOOR };
+			}
+			if entering && tiles[pos.x][pos.y].is_floor() && self.door(rng) {
+				tiles[pos.x][pos.y] = DOOR;
+			}
+
+			pos = next;
+			last_dir = dir;
+		}
+	}
+
+	// Rooms and corridors, or None if fewer than two rooms fit
+	fn try_generate(&self, width : uint, height : uint, rng : @mut GameRng) -> Option<~[~[Tile]]> {
+		let mut tiles = vec::from_fn(width, |_| vec::from_elem(height, WALL));
+		let mut in_room = vec::from_fn(width, |_| vec::from_elem(height, false));
+		let mut rooms : ~[Room] = ~[];
+
+		for uint::range(0, self.max_rooms * 4) |_| {
+			if rooms.len() >= self.max_rooms {
+				break;
+			}
+			match self.place_room(width as int, height as int, rng) {
+				Some(room) => {
+					if !rooms.any(|r| r.overlaps(&room, 1)) {
+						do room.each_tile |p| {
+							tiles[p.x][p.y] = FLOOR;
+							in_room[p.x][p.y] = true;
+						}
+						rooms.push(room);
+					}
+				},
+				None => {}
+			}
+		}
+
+		if rooms.len() < 2 {
+			return None;
+		}
+
+		for uint::range(1, rooms.len()) |i| {
+			let center = rooms[i].center();
+			let mut nearest = 0;
+			for uint::range(1, i) |j| {
+				if rooms[j].center().distance(&center) <
+						rooms[nearest].center().distance(&center) {
+					nearest = j;
+				}
+			}
+			self.carve_corridor(&mut tiles, &in_room, center, rooms[nearest].center(), rng);
+		}
+		Some(tiles)
+	}
+}
+
+impl MapGenerator for RoomsGenerator {
+	fn generate(&mut self, width : uint, height : uint, topology : Topology,
+			rng : @mut GameRng) -> ~[~[Tile]] {
+		for uint::range(0, self.max_attempts) |_| {
+			match self.try_generate(width, height, rng) {
+				Some(tiles) => return tiles,
+				None => {}
+			}
+		}
+		// Too cramped for two rooms
+		CaveGenerator::new().generate(width, height, topology, rng)
+	}
+}
@@ -0,0 +1,36 @@
+/**
+ * What happened in the game, as text for the player
+ */
+pub struct MessageLog {
+	messages : ~[Message],
+	// Number of messages ever added
+	total : uint
+}
+
+pub struct Message {
+	tick : uint,
+	text : ~str
+}
+
+// Messages kept for looking back
+static LOG_SIZE : uint = 100;
+
+pub impl MessageLog {
+	fn new() -> MessageLog {
+		MessageLog { messages: ~[], total: 0 }
+	}
+
+	fn add(&mut self, tick : uint, text : ~str) {
+		if self.messages.len() >= LOG_SIZE {
+			self.messages.shift();
+		}
+		self.messages.push(Message { tick: tick, text: text });
+		self.total += 1;
+	}
+
+	// Messages added after the first `seen` ones, as far as they are kept
+	fn since(&self, seen : uint) -> ~[Message] {
+		let new = uint::min(self.total - seen, self.messages.len());
+		vec::from_slice(self.messages.slice(self.messages.len() - new, self.messages.len()))
+	}
+}
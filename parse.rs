@@ -0,0 +1,23 @@
+/*
+ * Pieces shared by the readers of the text files: save files, replays
+ * and species. Errors are messages starting with the line they are on.
+ */
+
+// Value of an Ok, or return the error of an Err from the enclosing function
+macro_rules! try (
+	($e:expr) => (match $e { Ok(v) => v, Err(e) => return Err(e) })
+)
+
+pub fn parse_uint(s : &str, line : uint) -> Result<uint, ~str> {
+	match uint::from_str(s) {
+		Some(n) => Ok(n),
+		None => Err(fmt!("line %u: expected a number, got '%s'", line, s))
+	}
+}
+
+pub fn parse_int(s : &str, line : uint) -> Result<int, ~str> {
+	match int::from_str(s) {
+		Some(n) => Ok(n),
+		None => Err(fmt!("line %u: expected a number, got '%s'", line, s))
+	}
+}
@@ -0,0 +1,210 @@
+use core::hashmap::linear::LinearMap;
+use core::vec;
+use std::priority_queue::PriorityQueue;
+
+use map;
+use map::{Action, Creature, Direction, Map, MapView, Position};
+
+/*
+ * A* pathfinding
+ *
+ * Turning takes time in this game, so the search runs over (position,
+ * direction) states and every step is an action with its cost in ticks:
+ * moving in one of the four directions a creature can move without
+ * turning, or turning left or right.
+ */
+
+static STEPS : [Action, ..6] = [
+	map::MOVE(map::FORWARD), map::MOVE(map::BACKWARD),
+	map::MOVE(map::LEFT), map::MOVE(map::RIGHT),
+	map::TURN(map::LEFT), map::TURN(map::RIGHT)
+];
+
+pub struct PathOptions {
+	// Tiles with a creature on them are impassable, except for the goal
+	creatures_block : bool,
+	// Only go through tiles this creature knows about
+	known_by : Option<@mut Creature>,
+	// Give up after looking at this many states
+	max_nodes : uint
+}
+
+pub impl PathOptions {
+	fn new() -> PathOptions {
+		PathOptions { creatures_block: false, known_by: None, max_nodes: 10000 }
+	}
+}
+
+/**
+ * Actions leading to the goal, with the position after each of them
+ */
+pub struct Path {
+	actions : ~[Action],
+	positions : ~[Position],
+	// Total time in ticks
+	cost : uint
+}
+
+// Tile and direction of a search state
+type Key = (int, int, uint);
+
+/**
+ * Entry of a `PriorityQueue`, which pops the entry with the lowest
+ * priority first
+ */
+pub struct Lowest<T> {
+	priority : int,
+	item : T
+}
+
+// Ordered backwards, as the priority queue pops the greatest entry
+impl<T> Ord for Lowest<T> {
+	fn lt(&self, other : &Lowest<T>) -> bool { self.priority > other.priority }
+	fn le(&self, other : &Lowest<T>) -> bool { self.priority >= other.priority }
+	fn gt(&self, other : &Lowest<T>) -> bool { self.priority < other.priority }
+	fn ge(&self, other : &Lowest<T>) -> bool { self.priority <= other.priority }
+}
+
+struct Node {
+	pos : Position,
+	dir : Direction,
+	cost : uint
+}
+
+struct Step {
+	parent : Key,
+	pos : Position,
+	action : Action
+}
+
+fn key(pos : &Position, dir : Direction) -> Key {
+	(pos.x, pos.y, dir.to_uint())
+}
+
+// Cost in ticks of one step
+pub fn step_cost(action : Action) -> uint {
+	action.pre_ticks() + action.post_ticks()
+}
+
+// Cheapest step through a tile, for estimates that never overshoot
+fn min_step_cost() -> uint {
+	step_cost(map::MOVE(map::FORWARD))
+}
+
+/**
+ * Cheapest way from `from`, facing `dir`, to a tile for which `goal` is
+ * true, on any map view. `goal` and `heuristic` get canonical positions;
+ * `heuristic` estimates the remaining cost in ticks and must not
+ * overestimate it.
+ */
+pub fn search<M:MapView>(view : &mut M, from : &Position, dir : Direction,
+		goal : &fn(&Position) -> bool, heuristic : &fn(&Position) -> uint,
+		opts : &PathOptions) -> Option<Path> {
+	let start = match view.canonical(from) {
+		Some(p) => key(&p, dir),
+		None => return None
+	};
+
+	let mut open = PriorityQueue::new();
+	let mut costs : LinearMap<Key, uint> = LinearMap::new();
+	let mut steps : LinearMap<Key, Step> = LinearMap::new();
+	let mut expanded = 0;
+
+	costs.insert(start, 0);
+	// Prioritized by the cost plus the estimate of the rest of the way
+	open.push(Lowest { priority: 0, item: Node { pos: *from, dir: dir, cost: 0 } });
+
+	while !open.is_empty() {
+		let node = open.pop().item;
+		let canonical = view.canonical(&node.pos).get();
+		let node_key = key(&canonical, node.dir);
+
+		// Already reached in a cheaper way
+		if *costs.get(&node_key) < node.cost {
+			loop;
+		}
+
+		if goal(&canonical) {
+			return Some(rebuild(&steps, start, node_key, node.cost));
+		}
+
+		expanded += 1;
+		if expanded > opts.max_nodes {
+			return None;
+		}
+
+		for STEPS.each |&action| {
+			let (pos, d) = match action {
+				map::MOVE(rd) => (node.pos.neighbor(node.dir.turn(rd)), node.dir),
+				map::TURN(rd) => (node.pos, node.dir.turn(rd)),
+				_ => (node.pos, node.dir)
+			};
+
+			let next = match view.canonical(&pos) {
+				Some(p) => p,
+				None => loop
+			};
+			if !view.at(&pos).is_passable() {
+				loop;
+			}
+			if opts.creatures_block && pos != node.pos &&
+					view.creature_at(&pos).is_some() && !goal(&next) {
+				loop;
+			}
+			match opts.known_by {
+				Some(cr) if !cr.knows(&view.translate(&pos)) => loop,
+				_ => {}
+			}
+
+			let next_key = key(&next, d);
+			let cost = node.cost + step_cost(action);
+			let better = match costs.find(&next_key) {
+				Some(&c) => cost < c,
+				None => true
+			};
+			if better {
+				costs.insert(next_key, cost);
+				steps.insert(next_key, Step { parent: node_key, pos: pos, action: action });
+				open.push(Lowest {
+					priority: (cost + heuristic(&next)) as int,
+					item: Node { pos: pos, dir: d, cost: cost }
+				});
+			}
+		}
+	}
+	None
+}
+
+fn rebuild(steps : &LinearMap<Key, Step>, start : Key, end : Key, cost : uint) -> Path {
+	let mut actions = ~[];
+	let mut positions = ~[];
+	let mut k = end;
+
+	while k != start {
+		let step = steps.get(&k);
+		actions.push(step.action);
+		positions.push(step.pos);
+		k = step.parent;
+	}
+	vec::reverse(actions);
+	vec::reverse(positions);
+
+	Path { actions: actions, positions: positions, cost: cost }
+}
+
+pub impl Map {
+	// Cheapest path to `to`, wrapping around a torus
+	fn find_path(&mut self, from : &Position, dir : Direction, to : &Position,
+			opts : &PathOptions) -> Option<Path> {
+		let to = match self.normalize(to) {
+			Some(p) => p,
+			None => return None
+		};
+		let topology = self.topology;
+		let (width, height) = (self.width, self.height);
+
+		search(self, from, dir, |p| *p == to, |p| {
+			topology.distance(width, height, p, &to) as uint * min_step_cost()
+		}, opts)
+	}
+}
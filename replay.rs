@@ -0,0 +1,228 @@
+use core::io;
+use core::io::{Reader, ReaderUtil, Writer, WriterUtil};
+use core::str;
+use core::to_str::ToStr;
+
+use map;
+use map::{Action, Creature, MoveController, Topology};
+use dungeon::LevelPolicy;
+use parse;
+
+// Bumped whenever the header or the rules of the game change, since old
+// replays would go out of sync
+static REPLAY_VERSION : uint = 5;
+
+/**
+ * Writes every action returned by the creatures' controllers to
+ * a replay file.
+ *
+ * File format (one record per line):
+ *
+ *     rustyhex-replay <version>
+ *     seed <seed>
+ *     map <width> <height> <topology> <generator>
+ *     dungeon <levels> <level policy>
+ *     player <creature id>
+ *     <tick> <creature id> <action>
+ *     ...
+ *     end <tick>
+ *
+ * The generator comes last on its line as `file:` paths may have spaces.
+ */
+pub struct Recorder {
+	out : @io::Writer
+}
+
+pub impl Recorder {
+	fn create(path : &Path, seed : u32, generator : &str, width : uint, height : uint,
+			topology : Topology, depth : uint, policy : LevelPolicy
+			) -> Result<Recorder, ~str> {
+		match io::file_writer(path, [io::Create, io::Truncate]) {
+			Ok(out) => {
+				out.write_line(fmt!("rustyhex-replay %u", REPLAY_VERSION));
+				out.write_line(fmt!("seed %u", seed as uint));
+				out.write_line(fmt!("map %u %u %s %s", width, height, topology.to_str(),
+					generator));
+				out.write_line(fmt!("dungeon %u %s", depth, policy.to_str()));
+				Ok(Recorder { out: out })
+			},
+			Err(e) => Err(e)
+		}
+	}
+
+	fn set_player(&mut self, player : @mut Creature) {
+		self.out.write_line(fmt!("player %u", player.id));
+	}
+
+	fn record(&mut self, tick : uint, id : uint, action : Action) {
+		self.out.write_line(fmt!("%u %u %s", tick, id, action.to_str()));
+	}
+
+	fn finish(&mut self, tick : uint) {
+		self.out.write_line(fmt!("end %u", tick));
+		self.out.flush();
+	}
+}
+
+/**
+ * Player actions read back from a replay file
+ */
+pub struct Replay {
+	seed : u32,
+	generator : ~str,
+	width : uint,
+	height : uint,
+	topology : Topology,
+	depth : uint,
+	policy : LevelPolicy,
+	end : Option<uint>,
+	actions : ~[(uint, Action)],
+	next : uint
+}
+
+pub impl Replay {
+	fn load(path : &Path) -> Result<Replay, ~str> {
+		let input = match io::file_reader(path) {
+			Ok(input) => input,
+			Err(e) => return Err(e)
+		};
+
+		let mut version = false;
+		let mut seed = None;
+		let mut setup = None;
+		let mut levels = None;
+		let mut player = None;
+		let mut end = None;
+		let mut actions = ~[];
+		let mut line = 0;
+
+		while !input.eof() {
+			let text = input.read_line();
+			line += 1;
+
+			let words = str::words(text);
+			if words.len() == 0 {
+				loop;
+			}
+
+			match words[0] {
+				~"rustyhex-replay" => {
+					if words.len() != 2 || words[1] != REPLAY_VERSION.to_str() {
+						return Err(fmt!("line %u: unsupported replay version", line));
+					}
+					version = true;
+				},
+				~"seed" if words.len() == 2 => {
+					match u32::from_str(words[1]) {
+						Some(s) => seed = Some(s),
+						None => return Err(fmt!("line %u: invalid seed", line))
+					}
+				},
+				~"map" if words.len() >= 5 => {
+					let (width, height) = match (uint::from_str(words[1]), uint::from_str(words[2])) {
+						(Some(w), Some(h)) => (w, h),
+						_ => return Err(fmt!("line %u: invalid map size", line))
+					};
+					let topology = match Topology::parse(words[3]) {
+						Some(t) => t,
+						None => return Err(fmt!("line %u: invalid topology", line))
+					};
+					let generator = str::connect(words.slice(4, words.len()), " ");
+					setup = Some((generator, width, height, topology));
+				},
+				~"dungeon" if words.len() == 3 => {
+					match (uint::from_str(words[1]), LevelPolicy::parse(words[2])) {
+						(Some(d), Some(p)) if d > 0 => levels = Some((d, p)),
+						_ => return Err(fmt!("line %u: invalid dungeon", line))
+					}
+				},
+				~"player" if words.len() == 2 => {
+					player = Some(try!(parse::parse_uint(words[1], line)));
+				},
+				~"end" if words.len() == 2 => {
+					end = Some(try!(parse::parse_uint(words[1], line)));
+				},
+				_ if words.len() >= 3 => {
+					let tick = try!(parse::parse_uint(words[0], line));
+					let id = try!(parse::parse_uint(words[1], line));
+					let action = str::connect(words.slice(2, words.len()), " ");
+					let action = match Action::parse(action) {
+						Some(action) => action,
+						None => return Err(fmt!("line %u: invalid action '%s'", line, action))
+					};
+					if Some(id) == player {
+						actions.push((tick, action));
+					}
+				},
+				_ => {
+					return Err(fmt!("line %u: can't parse '%s'", line, text));
+				}
+			}
+		}
+
+		if !version {
+			return Err(~"not a replay or of an unknown version");
+		}
+		match (seed, setup, levels, player) {
+			(Some(seed), Some((generator, width, height, topology)), Some((depth, policy)), Some(_)) =>
+				Ok(Replay {
+					seed: seed, generator: generator, width: width, height: height,
+					topology: topology, depth: depth, policy: policy,
+					end: end, actions: actions, next: 0
+				}),
+			_ => Err(~"replay has no seed, map, dungeon or player")
+		}
+	}
+
+	fn finished(&self) -> bool {
+		self.next >= self.actions.len()
+	}
+
+	fn next_action(&mut self, tick : uint) -> Option<Action> {
+		if self.finished() {
+			return None;
+		}
+
+		let (recorded_tick, action) = self.actions[self.next];
+		self.next += 1;
+
+		if recorded_tick != tick {
+			io::stderr().write_line(fmt!(
+				"replay out of sync: action recorded at tick %u, replayed at %u",
+				recorded_tick, tick
+			));
+		}
+		Some(action)
+	}
+}
+
+/**
+ * Feeds the player's recorded actions back.
+ *
+ * Monsters are simulated again from the seed, so the replay stays in sync
+ * as long as the recorded player wasn't itself drawing from the map's RNG;
+ * the AI of a headless player has its own. Once the recording runs out the
+ * player just waits.
+ */
+pub struct ReplayController {
+	replay : @mut Replay
+}
+
+pub impl ReplayController {
+	fn new(replay : @mut Replay) -> ReplayController {
+		ReplayController { replay: replay }
+	}
+}
+
+impl MoveController for ReplayController {
+	fn get_move(&mut self, cr : @mut Creature) -> Action {
+		match self.replay.next_action(cr.map.ticks) {
+			Some(action) => action,
+			None => map::WAIT
+		}
+	}
+
+	fn type_tag(&self) -> &'static str {
+		"replay"
+	}
+}
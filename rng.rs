@@ -0,0 +1,66 @@
+use core::rand;
+
+/**
+ * Seedable xorshift random number generator
+ *
+ * Everything random in a game (map generation, spawning, AI) draws from
+ * a single instance of this, so a game can be replayed from its seed.
+ * Unlike `core::rand::Rng` its state is plain data.
+ */
+pub struct GameRng {
+	seed : u32,
+	x : u32,
+	y : u32,
+	z : u32,
+	w : u32
+}
+
+pub impl GameRng {
+	fn new(seed : u32) -> GameRng {
+		let mut rng = GameRng {
+			seed: seed,
+			x: seed ^ 123456789,
+			y: 362436069,
+			z: 521288629,
+			w: 88675123
+		};
+
+		// Spread the seed over the whole state
+		for uint::range(0, 16) |_| {
+			rng.next();
+		}
+		rng
+	}
+
+	// Seed for games started without one
+	fn random_seed() -> u32 {
+		rand::Rng().next()
+	}
+
+	fn next(&mut self) -> u32 {
+		let t = self.x ^ (self.x << 11);
+		self.x = self.y;
+		self.y = self.z;
+		self.z = self.w;
+		self.w = self.w ^ (self.w >> 19) ^ (t ^ (t >> 8));
+		self.w
+	}
+
+	// Random int in [start, end)
+	fn gen_int_range(&mut self, start : int, end : int) -> int {
+		assert!(start < end);
+		// Unsigned modulo: as an int, the u32 would be negative half the
+		// time on 32 bit targets
+		start + (self.next() % ((end - start) as u32)) as int
+	}
+
+	// Random uint in [start, end)
+	fn gen_uint_range(&mut self, start : uint, end : uint) -> uint {
+		assert!(start < end);
+		start + (self.next() as uint) % (end - start)
+	}
+
+	fn gen_bool(&mut self) -> bool {
+		self.next() & 1 == 1
+	}
+}
@@ -0,0 +1,491 @@
+use core::io;
+use core::io::{Reader, ReaderUtil, Writer, WriterUtil};
+use core::str;
+use core::vec;
+use core::to_str::ToStr;
+
+use map;
+use map::{Map, Creature, MoveController, Action, Tile};
+use rng::GameRng;
+use engine::{Engine, Renderer};
+use dungeon;
+use dungeon::Dungeon;
+use fov::VisionProfile;
+use combat::Stats;
+use item;
+use item::Item;
+use status::StatusKind;
+use species::Behaviour;
+use effect::Effect;
+use parse;
+
+static SAVE_VERSION : uint = 11;
+
+/*
+ * Save file format, one record per line:
+ *
+ *     rustyhex-save <version>
+ *     dungeon <levels> <level policy> <ticks> <next creature id>
+ *     rng <seed> <x> <y> <z> <w>
+ *     level <width> <height> <topology>
+ *     <height lines of tiles, one char per tile: '.' floor, '#' wall, '+' door,
+ *         '>' stairs down, '<' stairs up>
+ *     items <count>
+ *     item <x> <y> <name>
+ *     ...
+ *     ...
+ *     creatures <count>
+ *     creature <id> <depth> <controller tag> <faction> <x> <y> <dir> <life> \
+ *         <last hit time> <pre action ticks> <post action ticks> \
+ *         <vision radius> <vision cone> <dark penalty> <action or '-'>
+ *     stats <max hp> <attack> <defence> <accuracy> <evasion> <range> <name>
+ *     species <speed> <sprite row> <behaviour> <effect of a hit or '-'>
+ *     statuses <count>
+ *     status <kind> <ticks left>
+ *     ...
+ *     inventory <count>
+ *     carried <item name>
+ *     ...
+ *     equipment <count>
+ *     worn <slot> <item name>
+ *     ...
+ *     <height lines of known tiles: '1' known, '0' unknown>
+ *     memories <count>
+ *     memory <depth>
+ *     <known tiles of that level>
+ *     ...
+ *     player <id>
+ *
+ * Creatures are stored in the order the engine ticks them. Controllers
+ * are stored by their type tag and rebuilt by the caller on load.
+ */
+
+fn write_grid<T>(out : @io::Writer, grid : &~[~[T]], width : uint, height : uint,
+		f : &fn(&T) -> char) {
+	for uint::range(0, height) |y| {
+		let mut line = ~"";
+		for uint::range(0, width) |x| {
+			str::push_char(&mut line, f(&grid[x][y]));
+		}
+		out.write_line(line);
+	}
+}
+
+fn write_creature(out : @io::Writer, cr : @mut Creature) {
+	let action = match cr.action {
+		Some(action) => action.to_str(),
+		None => ~"-"
+	};
+
+	out.write_line(fmt!("creature %u %u %s %s %d %d %u %d %d %u %u %u %u %u %s",
+		cr.id, cr.map.depth, cr.controller.type_tag(), cr.faction.to_str(),
+		cr.pos.x, cr.pos.y, cr.dir.to_uint(), cr.life, cr.last_hit_time,
+		cr.pre_action_ticks, cr.post_action_ticks,
+		cr.vision.radius, cr.vision.cone, cr.vision.dark_penalty, action
+	));
+	out.write_line(fmt!("stats %d %d %d %d %d %d %s",
+		cr.stats.max_hp, cr.stats.attack, cr.stats.defence,
+		cr.stats.accuracy, cr.stats.evasion, cr.stats.range, cr.name
+	));
+	let on_hit = match cr.on_hit {
+		Some(effect) => effect.to_str(),
+		None => ~"-"
+	};
+	out.write_line(fmt!("species %u %u %s %s", cr.speed, cr.sprite,
+		cr.behaviour.to_str(), on_hit));
+	out.write_line(fmt!("statuses %u", cr.statuses.len()));
+	for cr.statuses.each |s| {
+		out.write_line(fmt!("status %s %u", s.kind.to_str(), s.ticks));
+	}
+	out.write_line(fmt!("inventory %u", cr.inventory.len()));
+	for cr.inventory.each |it| {
+		out.write_line(fmt!("carried %s", it.name));
+	}
+	let worn = do vec::filter_mapi(cr.equipment) |i, it| {
+		match *it {
+			Some(it) => Some((item::SLOTS[i], it)),
+			None => None
+		}
+	};
+	out.write_line(fmt!("equipment %u", worn.len()));
+	for worn.each |&(slot, it)| {
+		out.write_line(fmt!("worn %s %s", slot.to_str(), it.name));
+	}
+	write_known(out, &cr.map_known);
+
+	let memories = do vec::filter_mapi(cr.level_memory) |depth, known| {
+		match *known {
+			Some(ref known) => Some((depth, known)),
+			None => None
+		}
+	};
+	out.write_line(fmt!("memories %u", memories.len()));
+	for memories.each |&(depth, known)| {
+		out.write_line(fmt!("memory %u", depth));
+		write_known(out, known);
+	}
+}
+
+fn write_known(out : @io::Writer, known : &~[~[bool]]) {
+	write_grid(out, known, known.len(), known[0].len(),
+		|&known| if known { '1' } else { '0' });
+}
+
+pub fn save_game(path : &Path, engine : &Engine) -> Result<(), ~str> {
+	let out = match io::file_writer(path, [io::Create, io::Truncate]) {
+		Ok(out) => out,
+		Err(e) => return Err(e)
+	};
+	let dungeon = &engine.dungeon;
+	let top = dungeon.top();
+	let rng = top.rng;
+
+	out.write_line(fmt!("rustyhex-save %u", SAVE_VERSION));
+	out.write_line(fmt!("dungeon %u %s %u %u", dungeon.levels.len(),
+		dungeon.policy.to_str(), top.ticks, *top.next_creature_id));
+	out.write_line(fmt!("rng %u %u %u %u %u", rng.seed as uint,
+		rng.x as uint, rng.y as uint, rng.z as uint, rng.w as uint));
+	for dungeon.levels.each |&map| {
+		out.write_line(fmt!("level %u %u %s", map.width, map.height, map.topology.to_str()));
+		write_grid(out, &map.tiles, map.width, map.height, |t| t.to_char());
+
+		let mut items = ~[];
+		for uint::range(0, map.width) |x| {
+			for uint::range(0, map.height) |y| {
+				for map.items[x][y].each |it| {
+					items.push(fmt!("item %u %u %s", x, y, it.name));
+				}
+			}
+		}
+		out.write_line(fmt!("items %u", items.len()));
+		for items.each |line| {
+			out.write_line(*line);
+		}
+	}
+
+	let alive = do engine.creatures.filtered |&cr| { cr.alive() };
+	out.write_line(fmt!("creatures %u", alive.len()));
+	for alive.each |&cr| {
+		write_creature(out, cr);
+	}
+
+	match engine.player {
+		Some(player) => out.write_line(fmt!("player %u", player.id)),
+		None => {}
+	}
+	out.flush();
+	Ok(())
+}
+
+/**
+ * Line based reader keeping track of the position for error messages
+ */
+struct SaveReader {
+	input : @io::Reader,
+	line : uint
+}
+
+pub impl SaveReader {
+	fn error<T>(&self, msg : ~str) -> Result<T, ~str> {
+		Err(fmt!("line %u: %s", self.line, msg))
+	}
+
+	fn read_line(&mut self) -> Result<~str, ~str> {
+		if self.input.eof() {
+			return self.error(~"unexpected end of file");
+		}
+		self.line += 1;
+		Ok(self.input.read_line())
+	}
+
+	// Read a line starting with `keyword` followed by `n` arguments
+	fn read_record(&mut self, keyword : &str, n : uint) -> Result<~[~str], ~str> {
+		let line = match self.read_line() {
+			Ok(line) => line,
+			Err(e) => return Err(e)
+		};
+		let words = str::words(line);
+		if words.len() < n + 1 || words[0] != keyword.to_owned() {
+			return self.error(fmt!("expected '%s' record", keyword));
+		}
+		Ok(vec::from_slice(words.slice(1, words.len())))
+	}
+
+	fn parse_int(&self, s : &str) -> Result<int, ~str> {
+		parse::parse_int(s, self.line)
+	}
+
+	fn parse_uint(&self, s : &str) -> Result<uint, ~str> {
+		parse::parse_uint(s, self.line)
+	}
+
+	fn read_grid<T:Copy>(&mut self, width : uint, height : uint, default : T,
+			f : &fn(char) -> Option<T>) -> Result<~[~[T]], ~str> {
+		let mut grid = vec::from_elem(width, vec::from_elem(height, default));
+
+		for uint::range(0, height) |y| {
+			let line = match self.read_line() {
+				Ok(line) => line,
+				Err(e) => return Err(e)
+			};
+			if line.len() != width {
+				return self.error(fmt!("expected %u characters", width));
+			}
+			for uint::range(0, width) |x| {
+				match f(line.char_at(x)) {
+					Some(v) => grid[x][y] = v,
+					None => return self.error(fmt!("invalid character at column %u", x + 1))
+				}
+			}
+		}
+		Ok(grid)
+	}
+}
+
+fn read_known(r : &mut SaveReader, map : @mut Map) -> Result<~[~[bool]], ~str> {
+	r.read_grid(map.width, map.height, false, |c| {
+		match c {
+			'1' => Some(true),
+			'0' => Some(false),
+			_ => None
+		}
+	})
+}
+
+// Item of the catalogue called by the words of `args` from `start` on
+fn read_item(r : &SaveReader, args : &[~str], start : uint) -> Result<Item, ~str> {
+	let name = str::connect(args.slice(start, args.len()), " ");
+	match Item::find(name) {
+		Some(it) => Ok(it),
+		None => r.error(fmt!("unknown item '%s'", name))
+	}
+}
+
+fn read_creature(r : &mut SaveReader, dungeon : &Dungeon,
+		controller : &fn(&str) -> Option<@MoveController>
+		) -> Result<@mut Creature, ~str> {
+	let args = try!(r.read_record("creature", 15));
+
+	let id = try!(r.parse_uint(args[0]));
+	let depth = try!(r.parse_uint(args[1]));
+	if depth >= dungeon.levels.len() {
+		return r.error(fmt!("no level %u", depth));
+	}
+	let map = dungeon.levels[depth];
+	let ctr = match controller(args[2]) {
+		Some(ctr) => ctr,
+		None => return r.error(fmt!("unknown controller '%s'", args[2]))
+	};
+	let faction = match map::Faction::parse(args[3]) {
+		Some(faction) => faction,
+		None => return r.error(fmt!("unknown faction '%s'", args[3]))
+	};
+	let pos = map::Position {
+		x: try!(r.parse_int(args[4])),
+		y: try!(r.parse_int(args[5]))
+	};
+	if !map.contains(&pos) {
+		return r.error(~"creature beyond the edge of the map");
+	}
+	if !map.at(&pos).is_passable() {
+		return r.error(fmt!("creature in a wall at %d,%d", pos.x, pos.y));
+	}
+	let dir = map::N.turn_by_int(try!(r.parse_int(args[6])));
+
+	let cr = @mut Creature::with_controller(map, &pos, dir, ctr);
+	cr.id = id;
+	cr.faction = faction;
+	cr.life = try!(r.parse_int(args[7]));
+	cr.last_hit_time = try!(r.parse_int(args[8]));
+	cr.pre_action_ticks = try!(r.parse_uint(args[9]));
+	cr.post_action_ticks = try!(r.parse_uint(args[10]));
+	cr.vision = VisionProfile {
+		radius: try!(r.parse_uint(args[11])),
+		cone: try!(r.parse_uint(args[12])),
+		dark_penalty: try!(r.parse_uint(args[13]))
+	};
+	if !VisionProfile::is_valid_cone(cr.vision.cone) {
+		return r.error(fmt!("invalid vision cone %u", cr.vision.cone));
+	}
+	cr.action = if args[14] == ~"-" {
+		None
+	} else {
+		let action = str::connect(args.slice(14, args.len()), " ");
+		match Action::parse(action) {
+			Some(action) => Some(action),
+			None => return r.error(fmt!("invalid action '%s'", action))
+		}
+	};
+
+	let args = try!(r.read_record("stats", 7));
+	cr.stats = Stats {
+		max_hp: try!(r.parse_int(args[0])),
+		attack: try!(r.parse_int(args[1])),
+		defence: try!(r.parse_int(args[2])),
+		accuracy: try!(r.parse_int(args[3])),
+		evasion: try!(r.parse_int(args[4])),
+		range: try!(r.parse_int(args[5]))
+	};
+	cr.name = str::connect(args.slice(6, args.len()), " ");
+
+	let args = try!(r.read_record("species", 4));
+	cr.speed = try!(r.parse_uint(args[0]));
+	cr.sprite = try!(r.parse_uint(args[1]));
+	cr.behaviour = match Behaviour::parse(args[2]) {
+		Some(b) => b,
+		None => return r.error(fmt!("unknown behaviour '%s'", args[2]))
+	};
+	cr.on_hit = if args[3] == ~"-" {
+		None
+	} else {
+		let text = str::connect(args.slice(3, args.len()), " ");
+		match Effect::parse(text) {
+			Some(effect) => Some(effect),
+			None => return r.error(fmt!("unknown effect '%s'", text))
+		}
+	};
+
+	let args = try!(r.read_record("statuses", 1));
+	let count = try!(r.parse_uint(args[0]));
+	for uint::range(0, count) |_| {
+		let args = try!(r.read_record("status", 2));
+		let kind = match StatusKind::parse(args[0]) {
+			Some(kind) => kind,
+			None => return r.error(fmt!("unknown status '%s'", args[0]))
+		};
+		cr.add_status(kind, try!(r.parse_uint(args[1])));
+	}
+
+	let args = try!(r.read_record("inventory", 1));
+	let count = try!(r.parse_uint(args[0]));
+	for uint::range(0, count) |_| {
+		let args = try!(r.read_record("carried", 1));
+		cr.inventory.push(try!(read_item(&*r, args, 0)));
+	}
+
+	let args = try!(r.read_record("equipment", 1));
+	let count = try!(r.parse_uint(args[0]));
+	for uint::range(0, count) |_| {
+		let args = try!(r.read_record("worn", 2));
+		let slot = match item::Slot::parse(args[0]) {
+			Some(slot) => slot,
+			None => return r.error(fmt!("unknown slot '%s'", args[0]))
+		};
+		let it = try!(read_item(&*r, args, 1));
+		if it.slot != Some(slot) {
+			return r.error(fmt!("the %s isn't worn as %s", it.name, args[0]));
+		}
+		cr.equipment[slot.to_uint()] = Some(it);
+	}
+
+	cr.map_known = try!(read_known(r, map));
+
+	let args = try!(r.read_record("memories", 1));
+	let count = try!(r.parse_uint(args[0]));
+	cr.level_memory = vec::from_elem(dungeon.levels.len(), None);
+	for uint::range(0, count) |_| {
+		let args = try!(r.read_record("memory", 1));
+		let depth = try!(r.parse_uint(args[0]));
+		if depth >= dungeon.levels.len() {
+			return r.error(fmt!("no level %u", depth));
+		}
+		cr.level_memory[depth] = Some(try!(read_known(r, dungeon.levels[depth])));
+	}
+
+	if !map.place_creature(cr) {
+		return r.error(~"two creatures on the same tile");
+	}
+	Ok(cr)
+}
+
+/**
+ * Load a game saved with `save_game`.
+ *
+ * `controller` maps the type tag of each creature's controller back
+ * to a controller instance.
+ */
+pub fn load_game(path : &Path, renderer : @mut Renderer,
+		controller : &fn(&str) -> Option<@MoveController>
+		) -> Result<Engine, ~str> {
+	let input = match io::file_reader(path) {
+		Ok(input) => input,
+		Err(e) => return Err(e)
+	};
+	let mut r = SaveReader { input: input, line: 0 };
+
+	let version = try!(r.read_record("rustyhex-save", 1));
+	if version[0] != SAVE_VERSION.to_str() {
+		return r.error(fmt!("unsupported save version %s", version[0]));
+	}
+
+	let args = try!(r.read_record("dungeon", 4));
+	let depth = try!(r.parse_uint(args[0]));
+	let policy = match dungeon::LevelPolicy::parse(args[1]) {
+		Some(policy) => policy,
+		None => return r.error(fmt!("unknown level policy '%s'", args[1]))
+	};
+	let ticks = try!(r.parse_uint(args[2]));
+	let next_creature_id = try!(r.parse_uint(args[3]));
+	if depth == 0 {
+		return r.error(~"dungeon without levels");
+	}
+
+	let args = try!(r.read_record("rng", 5));
+	let mut state = ~[];
+	for args.each |arg| {
+		state.push(try!(r.parse_uint(*arg)) as u32);
+	}
+	let rng = @mut GameRng {
+		seed: state[0], x: state[1], y: state[2], z: state[3], w: state[4]
+	};
+
+	let mut levels = ~[];
+	for uint::range(0, depth) |_| {
+		let args = try!(r.read_record("level", 3));
+		let width = try!(r.parse_uint(args[0]));
+		let height = try!(r.parse_uint(args[1]));
+		let topology = match map::Topology::parse(args[2]) {
+			Some(t) => t,
+			None => return r.error(fmt!("unknown topology '%s'", args[2]))
+		};
+		let tiles = try!(r.read_grid(width, height, map::FLOOR, Tile::from_char));
+
+		let map = @mut Map::from_tiles(tiles, topology, rng);
+		map.ticks = ticks;
+
+		let args = try!(r.read_record("items", 1));
+		let count = try!(r.parse_uint(args[0]));
+		for uint::range(0, count) |_| {
+			let args = try!(r.read_record("item", 3));
+			let pos = map::Position {
+				x: try!(r.parse_int(args[0])),
+				y: try!(r.parse_int(args[1]))
+			};
+			if !map.contains(&pos) {
+				return r.error(~"item beyond the edge of the map");
+			}
+			map.put_item(&pos, try!(read_item(&r, args, 2)));
+		}
+
+		levels.push(map);
+	}
+
+	let mut engine = Engine::new(Dungeon::new(levels, policy), renderer);
+
+	let args = try!(r.read_record("creatures", 1));
+	let count = try!(r.parse_uint(args[0]));
+	for uint::range(0, count) |_| {
+		let cr = try!(read_creature(&mut r, &engine.dungeon, controller));
+		engine.add_creature(cr);
+	}
+	*engine.dungeon.top().next_creature_id = next_creature_id;
+
+	let args = try!(r.read_record("player", 1));
+	let player_id = try!(r.parse_uint(args[0]));
+	match engine.creatures.find(|&cr| cr.id == player_id) {
+		Some(player) => engine.follow_player(player),
+		None => return r.error(fmt!("no creature with id %u", player_id))
+	}
+
+	Ok(engine)
+}
@@ -0,0 +1,274 @@
+use core::io;
+use core::str;
+use core::to_str::ToStr;
+
+use combat;
+use combat::Stats;
+use effect::Effect;
+use fov;
+use fov::VisionProfile;
+use parse;
+use rng::GameRng;
+
+/*
+ * Monster species
+ *
+ * Species are read from a data file (`data/species.txt`) of records,
+ * one per line, grouped under the `species` record they belong to:
+ *
+ *     species <name>
+ *     hp <max hit points, above 0>
+ *     attack <damage> [<effect of a hit, as in `effect::Effect::parse`>]
+ *     defence <defence>
+ *     accuracy <percent>
+ *     evasion <percent>
+ *     range <hexes a shot reaches>
+ *     speed <percent of the usual time actions take, above 0>
+ *     vision <radius> <cone> <dark penalty>
+ *     behaviour <hunter|coward|berserker|sentry>
+ *     sprite <row of creature sprites in tiles.png: 3, or 7 to 12>
+ *
+ * Every record but `species` is optional and defaults to the stats of
+ * an ordinary monster. Which species live on a level is given by
+ *
+ *     spawn <depth> <species>=<weight> ...
+ *
+ * Species named in `spawn` records can't have spaces in their names.
+ * Levels deeper than the last `spawn` record use that record. Lines
+ * starting with ';' are comments.
+ */
+
+/**
+ * How a species behaves towards its enemies, see `ai::Mind`
+ */
+#[deriving(Eq)]
+pub enum Behaviour {
+	// Chases enemies and runs when badly hurt
+	HUNTER,
+	// Runs as soon as it's hurt
+	COWARD,
+	// Never runs
+	BERSERKER,
+	// Stays where it is until an enemy shows up
+	SENTRY
+}
+
+pub impl Behaviour {
+	fn parse(s : &str) -> Option<Behaviour> {
+		match s {
+			"hunter" => Some(HUNTER),
+			"coward" => Some(COWARD),
+			"berserker" => Some(BERSERKER),
+			"sentry" => Some(SENTRY),
+			_ => None
+		}
+	}
+
+	// Percentage of its hit points at or below which a monster runs away
+	fn flee_percent(&self) -> int {
+		match *self {
+			COWARD => 60,
+			BERSERKER => 0,
+			_ => 25
+		}
+	}
+}
+
+impl ToStr for Behaviour {
+	fn to_str(&self) -> ~str {
+		match *self {
+			HUNTER => ~"hunter",
+			COWARD => ~"coward",
+			BERSERKER => ~"berserker",
+			SENTRY => ~"sentry"
+		}
+	}
+}
+
+pub struct Species {
+	name : ~str,
+	stats : Stats,
+	// Percentage of the usual time actions take
+	speed : uint,
+	vision : VisionProfile,
+	// Happens to creatures it hits
+	on_hit : Option<Effect>,
+	behaviour : Behaviour,
+	// Row of the creature sprites in tiles.png
+	sprite : uint
+}
+
+// Row of the sprites of monsters that don't say otherwise
+pub static DEFAULT_SPRITE : uint = 3;
+
+// Rows from here up to SPRITE_ROWS of tiles.png hold species' sprites
+static FIRST_SPECIES_SPRITE : uint = 7;
+static SPRITE_ROWS : uint = 13;
+
+pub static DEFAULT_SPECIES_PATH : &'static str = "data/species.txt";
+
+pub struct SpeciesRegistry {
+	species : ~[@Species],
+	// Indices into `species` with their weights, by depth
+	spawns : ~[~[(uint, uint)]]
+}
+
+fn new_species(name : ~str) -> Species {
+	Species {
+		name: name,
+		stats: combat::DEFAULT_STATS,
+		speed: 100,
+		vision: fov::DEFAULT_VISION,
+		on_hit: None,
+		behaviour: HUNTER,
+		sprite: DEFAULT_SPRITE
+	}
+}
+
+pub impl SpeciesRegistry {
+	fn parse(text : &str) -> Result<SpeciesRegistry, ~str> {
+		let mut species : ~[Species] = ~[];
+		let mut spawn_lines = ~[];
+
+		let mut line = 0;
+		for str::each_line(text) |l| {
+			line += 1;
+			let words = str::words(l);
+			if words.is_empty() || l.starts_with(";") {
+				loop;
+			}
+
+			let keyword = copy words[0];
+			let args = vec::from_slice(words.slice(1, words.len()));
+			if keyword == ~"species" {
+				if args.len() == 0 {
+					return Err(fmt!("line %u: species without a name", line));
+				}
+				species.push(new_species(str::connect(args, " ")));
+				loop;
+			}
+			if keyword == ~"spawn" {
+				spawn_lines.push((line, args));
+				loop;
+			}
+			if species.is_empty() || args.is_empty() {
+				return Err(fmt!("line %u: unexpected '%s'", line, keyword));
+			}
+
+			let s = &mut species[species.len() - 1];
+			match keyword {
+				~"hp" => {
+					s.stats.max_hp = try!(parse::parse_int(args[0], line));
+					if s.stats.max_hp <= 0 {
+						return Err(fmt!("line %u: hp must be above 0", line));
+					}
+				},
+				~"attack" => {
+					s.stats.attack = try!(parse::parse_int(args[0], line));
+					if args.len() > 1 {
+						let text = str::connect(args.slice(1, args.len()), " ");
+						s.on_hit = match Effect::parse(text) {
+							Some(effect) => Some(effect),
+							None => return Err(fmt!("line %u: unknown effect '%s'", line, text))
+						};
+					}
+				},
+				~"defence" => s.stats.defence = try!(parse::parse_int(args[0], line)),
+				~"accuracy" => s.stats.accuracy = try!(parse::parse_int(args[0], line)),
+				~"evasion" => s.stats.evasion = try!(parse::parse_int(args[0], line)),
+				~"range" => s.stats.range = try!(parse::parse_int(args[0], line)),
+				~"speed" => {
+					s.speed = try!(parse::parse_uint(args[0], line));
+					if s.speed == 0 {
+						return Err(fmt!("line %u: speed must be above 0", line));
+					}
+				},
+				~"vision" if args.len() == 3 => {
+					s.vision = VisionProfile {
+						radius: try!(parse::parse_uint(args[0], line)),
+						cone: try!(parse::parse_uint(args[1], line)),
+						dark_penalty: try!(parse::parse_uint(args[2], line))
+					};
+					if !VisionProfile::is_valid_cone(s.vision.cone) {
+						return Err(fmt!("line %u: invalid vision cone %u", line, s.vision.cone));
+					}
+				},
+				~"behaviour" => s.behaviour = match Behaviour::parse(args[0]) {
+					Some(b) => b,
+					None => return Err(fmt!("line %u: unknown behaviour '%s'", line, args[0]))
+				},
+				~"sprite" => {
+					s.sprite = try!(parse::parse_uint(args[0], line));
+					if s.sprite != DEFAULT_SPRITE &&
+							(s.sprite < FIRST_SPECIES_SPRITE || s.sprite >= SPRITE_ROWS) {
+						return Err(fmt!("line %u: no creature sprites in row %u", line, s.sprite));
+					}
+				},
+				_ => return Err(fmt!("line %u: unexpected '%s'", line, keyword))
+			}
+		}
+
+		if species.is_empty() {
+			return Err(~"no species");
+		}
+		let species = species.map(|s| @copy *s);
+
+		let mut spawns = ~[];
+		for spawn_lines.each |&(line, ref args)| {
+			if args.len() < 2 {
+				return Err(fmt!("line %u: spawn without species", line));
+			}
+			let depth = try!(parse::parse_uint(args[0], line));
+			if depth != spawns.len() {
+				return Err(fmt!("line %u: expected spawns of depth %u", line, spawns.len()));
+			}
+
+			let mut table = ~[];
+			for args.slice(1, args.len()).each |entry| {
+				let parts = str::split_char(*entry, '=');
+				if parts.len() != 2 {
+					return Err(fmt!("line %u: expected <species>=<weight>, got '%s'", line, *entry));
+				}
+				let i = match species.position(|s| s.name == parts[0]) {
+					Some(i) => i,
+					None => return Err(fmt!("line %u: unknown species '%s'", line, parts[0]))
+				};
+				table.push((i, try!(parse::parse_uint(parts[1], line))));
+			}
+			if table.all(|&(_, weight)| weight == 0) {
+				return Err(fmt!("line %u: nothing spawns", line));
+			}
+			spawns.push(table);
+		}
+		if spawns.is_empty() {
+			// Every species alike
+			spawns.push(vec::from_fn(species.len(), |i| (i, 1)));
+		}
+
+		Ok(SpeciesRegistry { species: species, spawns: spawns })
+	}
+
+	fn load(path : &Path) -> Result<SpeciesRegistry, ~str> {
+		match io::read_whole_file_str(path) {
+			Ok(text) => match SpeciesRegistry::parse(text) {
+				Ok(registry) => Ok(registry),
+				Err(e) => Err(fmt!("%s: %s", path.to_str(), e))
+			},
+			Err(e) => Err(e)
+		}
+	}
+
+	// Species of a monster spawned on the level at `depth`
+	fn pick(&self, depth : uint, rng : @mut GameRng) -> @Species {
+		let table = &self.spawns[uint::min(depth, self.spawns.len() - 1)];
+		let total = table.foldl(0, |&sum, &(_, weight)| sum + weight);
+		let mut roll = rng.gen_uint_range(0, total);
+		for table.each |&(i, weight)| {
+			if roll < weight {
+				return self.species[i];
+			}
+			roll -= weight;
+		}
+		self.species[table[0].first()]
+	}
+}
@@ -0,0 +1,77 @@
+use core::to_str::ToStr;
+
+/**
+ * Lasting condition of a creature, counted down every tick
+ */
+#[deriving(Eq)]
+pub enum StatusKind {
+	// Loses a hit point every `PERIOD` ticks
+	POISON,
+	// Gains a hit point every `PERIOD` ticks
+	REGENERATION,
+	// Actions take twice as long
+	SLOW,
+	// Actions take half as long
+	HASTE,
+	// Can't do anything but wait
+	STUN,
+	// Sees nothing
+	BLIND,
+	// Moves and attacks go in random directions
+	CONFUSION
+}
+
+pub static STATUS_KINDS : [StatusKind, ..7] =
+	[POISON, REGENERATION, SLOW, HASTE, STUN, BLIND, CONFUSION];
+
+// Ticks between the hit points lost to poison or won by regeneration
+pub static PERIOD : uint = 20;
+
+pub struct Status {
+	kind : StatusKind,
+	// Ticks left
+	ticks : uint
+}
+
+pub impl StatusKind {
+	fn parse(s : &str) -> Option<StatusKind> {
+		for STATUS_KINDS.each |&kind| {
+			if kind.to_str() == s.to_owned() {
+				return Some(kind);
+			}
+		}
+		None
+	}
+
+	// Column of the icon the UI shows while the status lasts
+	fn icon(&self) -> uint {
+		*self as uint
+	}
+
+	// How it's said in the log: "you are ...", "the monster is ..."
+	fn adjective(&self) -> &'static str {
+		match *self {
+			POISON => "poisoned",
+			REGENERATION => "regenerating",
+			SLOW => "slowed down",
+			HASTE => "hasted",
+			STUN => "stunned",
+			BLIND => "blind",
+			CONFUSION => "confused"
+		}
+	}
+}
+
+impl ToStr for StatusKind {
+	fn to_str(&self) -> ~str {
+		match *self {
+			POISON => ~"poison",
+			REGENERATION => ~"regeneration",
+			SLOW => ~"slow",
+			HASTE => ~"haste",
+			STUN => ~"stun",
+			BLIND => ~"blind",
+			CONFUSION => ~"confusion"
+		}
+	}
+}
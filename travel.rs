@@ -0,0 +1,162 @@
+use map::{Action, Creature, Direction, Position, DIRECTIONS};
+use dijkstra::DijkstraMap;
+use path::PathOptions;
+
+enum Goal {
+	NOWHERE,
+	// Known tile to walk to
+	TO(Position),
+	// Nearest tiles the creature hasn't seen yet, until there are none left
+	EXPLORE
+}
+
+/**
+ * Walks a creature over many turns, stopping as soon as something
+ * happens that its player should look at: a creature that wasn't in
+ * view shows up or the creature gets hit.
+ */
+pub struct Travel {
+	goal : Goal,
+	// Level the travel started on
+	depth : uint,
+	// Ids of the creatures in view at the last step
+	seen : ~[uint],
+	// Creature's last_hit_time and the map's tick at the last step
+	last_hit_time : int,
+	last_tick : uint
+}
+
+// Can `cr` walk on `pos`, a position on the map, as far as it knows
+fn walkable(cr : @mut Creature, pos : &Position) -> bool {
+	cr.map_known[pos.x][pos.y] && cr.map.tiles[pos.x][pos.y].is_passable()
+}
+
+// Direction of a neighbour of `pos` that `cr` doesn't know yet
+fn unexplored_dir(cr : @mut Creature, pos : &Position) -> Option<Direction> {
+	for DIRECTIONS.each |&d| {
+		match cr.map.normalize(&pos.neighbor(d)) {
+			Some(n) if !cr.map_known[n.x][n.y] => return Some(d),
+			_ => {}
+		}
+	}
+	None
+}
+
+pub impl Travel {
+	fn new() -> Travel {
+		Travel { goal: NOWHERE, depth: 0, seen: ~[], last_hit_time: 0, last_tick: 0 }
+	}
+
+	fn is_active(&self) -> bool {
+		match self.goal {
+			NOWHERE => false,
+			_ => true
+		}
+	}
+
+	fn start(&mut self, cr : @mut Creature, target : &Position) {
+		self.goal = match cr.map.normalize(target) {
+			Some(target) => TO(target),
+			None => NOWHERE
+		};
+		self.depth = cr.map.depth;
+		self.remember(cr);
+	}
+
+	fn explore(&mut self, cr : @mut Creature) {
+		self.goal = EXPLORE;
+		self.depth = cr.map.depth;
+		self.remember(cr);
+	}
+
+	fn stop(&mut self) {
+		self.goal = NOWHERE;
+	}
+
+	fn remember(&mut self, cr : @mut Creature) {
+		self.seen = cr.visible_creatures().map(|c| c.id);
+		self.last_hit_time = cr.last_hit_time;
+		self.last_tick = cr.map.ticks;
+	}
+
+	// Has anything happened since the last step
+	fn interrupted(&self, cr : @mut Creature) -> bool {
+		// last_hit_time goes back to 0 on every hit
+		let elapsed = (cr.map.ticks - self.last_tick) as int;
+		if cr.last_hit_time < self.last_hit_time + elapsed {
+			return true;
+		}
+		cr.visible_creatures().any(|c| !self.seen.contains(&c.id))
+	}
+
+	// Next step, None once travel is over
+	fn next_action(&mut self, cr : @mut Creature) -> Option<Action> {
+		if !self.is_active() {
+			return None;
+		}
+		if cr.map.depth != self.depth || self.interrupted(cr) {
+			self.stop();
+			return None;
+		}
+		self.remember(cr);
+
+		let action = match self.goal {
+			TO(target) => self.step_to(cr, &target),
+			EXPLORE => self.explore_step(cr),
+			NOWHERE => None
+		};
+		if action.is_none() {
+			self.stop();
+		}
+		action
+	}
+
+	fn step_to(&self, cr : @mut Creature, target : &Position) -> Option<Action> {
+		if cr.map.normalize(&cr.pos) == Some(*target) {
+			return None;
+		}
+
+		// Enough to search every state of the map
+		let opts = PathOptions {
+			creatures_block: true, known_by: Some(cr),
+			max_nodes: cr.map.width * cr.map.height * 6
+		};
+		let pos = cr.pos;
+		match cr.map.find_path(&pos, cr.dir, target, &opts) {
+			Some(path) if !path.actions.is_empty() => Some(path.actions[0]),
+			_ => None
+		}
+	}
+
+	fn explore_step(&self, cr : @mut Creature) -> Option<Action> {
+		let pos = cr.pos;
+
+		// Look around before walking off
+		match unexplored_dir(cr, &pos) {
+			Some(d) if d != cr.dir => return Some(cr.action_towards(d)),
+			_ => {}
+		}
+
+		// Roll down a distance map over the known tiles, from the ones next
+		// to unknown tiles
+		let map = cr.map;
+		let mut frontier = ~[];
+		for uint::range(0, map.width) |x| {
+			for uint::range(0, map.height) |y| {
+				let p = Position {x: x as int, y: y as int};
+				if walkable(cr, &p) && unexplored_dir(cr, &p).is_some() {
+					frontier.push(p);
+				}
+			}
+		}
+		if frontier.is_empty() {
+			return None;
+		}
+
+		let flow = DijkstraMap::new_where(map, frontier, |p| walkable(cr, p));
+		match flow.downhill(map, &pos) {
+			Some(d) => Some(cr.action_towards(d)),
+			None => None
+		}
+	}
+}
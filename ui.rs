@@ -8,6 +8,12 @@ use sdl::Rect;
 
 use map;
 use map::MapView;
+use engine;
+use engine::Renderer;
+use messages::MessageLog;
+use item;
+use item::{Item, Slot};
+use status::StatusKind;
 
 /* replace with something more Rusty
  * in the future */
@@ -20,6 +26,21 @@ static SCREEN_WIDTH: uint = 800;
 static SCREEN_HEIGHT: uint = 600;
 static SCREEN_BPP: uint = 32;
 
+// Item sprites fill rows of tiles.png from row 4 on
+static ITEM_SPRITES_PER_ROW: uint = 8;
+
+// Layout of the inventory screen
+static INVENTORY_X: int = 40;
+static INVENTORY_Y: int = 40;
+static INVENTORY_COLUMNS: uint = 5;
+
+// Top left corner of the status icons
+static STATUS_X: int = 0;
+static STATUS_Y: int = 0;
+
+// Time a shot takes to cross one hex
+static SHOT_STEP_USEC: c_int = 30000;
+
 static HEX_WIDTH: uint = 66;
 static HEX_HEIGHT: uint = 56;
 static HEX_SIDE_WIDTH: uint = 16;
@@ -44,9 +65,27 @@ pub struct UI {
 	player : Option<@mut map::Creature>,
 	tiles : ~video::Surface,
 	view : ~View,
+	// Travel or fire target being picked, relative to the player like the view
+	cursor : Option<map::Position>,
+	// The cursor picks a hex to shoot at rather than to walk to
+	aiming : bool,
+	// Index of the selected item while the inventory screen is open
+	inventory : Option<uint>,
+	// Messages of the log already shown
+	messages_shown : uint,
 	exit : bool
 }
 
+/**
+ * What the player asked for
+ */
+pub enum Command {
+	ACT(map::Action),
+	// Walk to a known tile, in map coordinates
+	TRAVEL(map::Position),
+	EXPLORE
+}
+
 impl map::Position {
 	fn to_pix_x(&self) -> int {
 		self.x * ((HEX_WIDTH - HEX_SIDE_WIDTH) as int) + HEX_BORDER_WIDTH as int
@@ -78,7 +117,10 @@ impl Sprite {
 	fn for_tile(tile : map::Tile, visible : bool) -> Sprite {
 		let mut spr = match tile {
 				map::FLOOR => Sprite{ x: 0, y: 1 },
-				map::WALL => Sprite{ x: 0, y: 2 }
+				map::WALL => Sprite{ x: 0, y: 2 },
+				map::DOOR => Sprite{ x: 2, y: 1 },
+				map::STAIRS_DOWN => Sprite{ x: 4, y: 1 },
+				map::STAIRS_UP => Sprite{ x: 6, y: 1 }
 			};
 
 		if (!visible) {
@@ -86,8 +128,9 @@ impl Sprite {
 		}
 		spr
 	}
-	fn for_creature(dir : map::Direction) -> Sprite {
-		Sprite{ x: dir.to_uint(), y: 3 }
+	// Sprite of a creature with sprites in `row`, facing `dir` relative to the view
+	fn for_creature(dir : map::Direction, row : uint) -> Sprite {
+		Sprite{ x: dir.to_uint(), y: row }
 	}
 	fn for_hit() -> Sprite {
 		Sprite{ x: 0, y: 0 }
@@ -97,6 +140,23 @@ impl Sprite {
 		Sprite{ x: 1, y: 0 }
 	}
 
+	fn cursor() -> Sprite {
+		Sprite{ x: 2, y: 0 }
+	}
+
+	fn shot() -> Sprite {
+		Sprite{ x: 3, y: 0 }
+	}
+
+	// Icon shown while the player has the status
+	fn for_status(kind : StatusKind) -> Sprite {
+		Sprite{ x: kind.icon(), y: 6 }
+	}
+
+	fn for_item(it : &Item) -> Sprite {
+		Sprite{ x: it.sprite % ITEM_SPRITES_PER_ROW, y: 4 + it.sprite / ITEM_SPRITES_PER_ROW }
+	}
+
 	fn to_rect(&self) -> Rect {
 		Rect {
 			x: (HEX_FULL_WIDTH * self.x) as i16,
@@ -107,6 +167,18 @@ impl Sprite {
 	}
 }
 
+// Items on the inventory screen: the carried ones, then the worn ones with their slot
+fn inventory_entries(player : @mut map::Creature) -> ~[(Item, Option<Slot>)] {
+	let mut entries = player.inventory.map(|&it| (it, None));
+	for player.equipment.eachi |i, worn| {
+		match *worn {
+			Some(it) => entries.push((it, Some(item::SLOTS[i]))),
+			None => {}
+		}
+	}
+	entries
+}
+
 fn load_or_die(file : ~str) -> ~video::Surface {
 	match img::load(&Path(str::concat(&[~"data/", copy file, ~".png"]))) {
 		result::Ok(image) => {
@@ -138,6 +210,16 @@ pub impl View {
 		) { fail!(~"Failed blit_surface_rect") }
 	}
 
+	// Draw `sprite` with its top left corner at a pixel of the screen
+	fn draw_sprite_at(&self, dsurf: &video::Surface, ssurf: &video::Surface,
+		x : int, y : int, sprite : Sprite) {
+		let srect = sprite.to_rect();
+		let drect = Rect { x: x as i16, y: y as i16, w: srect.w, h: srect.h };
+		if !dsurf.blit_rect(ssurf, Some(srect), Some(drect)) {
+			fail!(~"Failed blit_surface_rect")
+		}
+	}
+
 	fn draw_sprite(&self, dsurf: &video::Surface, ssurf: &video::Surface,
 		pos : &map::Position, sprite : Sprite) {
 		let mut drect = pos.to_rect();
@@ -179,6 +261,10 @@ pub impl UI {
 			screen: screen,
 			player: None,
 			exit: false,
+			cursor: None,
+			aiming: false,
+			inventory: None,
+			messages_shown: 0,
 			view: ~View {
 			  x_offset: (SCREEN_WIDTH - HEX_FULL_WIDTH) as int / 2,
 			  y_offset: (SCREEN_HEIGHT - HEX_FULL_HEIGHT) as int * 7 / 8
@@ -187,19 +273,8 @@ pub impl UI {
 		}
 	}
 
-	fn set_player(&mut self, p : @mut map::Creature) {
-		self.player = Some(p);
-	}
-
-	fn update(&mut self) {
-
-		let player = match self.player {
-			Some(p) => p,
-			None => {
-				return;
-			}
-		};
-
+	// Draw what `player` sees, without showing it yet
+	fn draw(&mut self, player : @mut map::Creature) {
 		self.screen.fill(video::RGB(0, 0, 0));
 
 		let p = &*player;
@@ -214,6 +289,10 @@ pub impl UI {
 				self.view.draw_sprite(self.screen, self.tiles, pos, sprite);
 
 				if player.sees(tpos) {
+					match base.top_item(tpos) {
+						Some(it) => self.view.draw_sprite(self.screen, self.tiles, pos, Sprite::for_item(&it)),
+						None => {}
+					}
 					match base.creature_at(tpos) {
 						Some(creature) => {
 							if (creature.last_hit_time < 8) {
@@ -223,7 +302,7 @@ pub impl UI {
 							let d = player.dir; // workarounds
 							let cd = creature.dir;
 							let d = cd.relative_to(d);
-							let sprite = Sprite::for_creature(d);
+							let sprite = Sprite::for_creature(d, creature.sprite);
 							self.view.draw_sprite(self.screen, self.tiles, pos, sprite);
 						},
 						None => {}
@@ -236,10 +315,74 @@ pub impl UI {
 			self.view.draw_sprite(self.screen, self.tiles, &map::Position {x:0, y:0}, Sprite::human());
 		}
 
-		self.screen.flip();
+		match self.cursor {
+			Some(pos) => self.view.draw_sprite(self.screen, self.tiles, &pos, Sprite::cursor()),
+			None => {}
+		}
 
-		unsafe {
-			usleep(1000);
+		self.draw_statuses(player);
+	}
+
+	// Icons of the player's statuses along the top of the screen
+	fn draw_statuses(&mut self, player : @mut map::Creature) {
+		for player.statuses.eachi |i, s| {
+			let x = STATUS_X + (i * HEX_FULL_WIDTH) as int;
+			self.view.draw_sprite_at(self.screen, self.tiles, x, STATUS_Y, Sprite::for_status(s.kind));
+		}
+	}
+
+	// Draw the items `player` carries in place of the map, the selected one marked
+	// Carried items in rows at the top, worn items in a row below them
+	fn draw_inventory(&mut self, player : @mut map::Creature, selected : uint) {
+		self.screen.fill(video::RGB(0, 0, 0));
+
+		for inventory_entries(player).eachi |i, &(it, worn)| {
+			let (col, row) = match worn {
+				None => (i % INVENTORY_COLUMNS, i / INVENTORY_COLUMNS),
+				Some(slot) => (slot.to_uint(),
+					(item::INVENTORY_SIZE + INVENTORY_COLUMNS - 1) / INVENTORY_COLUMNS + 1)
+			};
+			let x = INVENTORY_X + (col * HEX_FULL_WIDTH) as int;
+			let y = INVENTORY_Y + (row * HEX_FULL_HEIGHT) as int;
+			self.view.draw_sprite_at(self.screen, self.tiles, x, y, Sprite::for_item(&it));
+			if i == selected {
+				self.view.draw_sprite_at(self.screen, self.tiles, x, y, Sprite::cursor());
+			}
+		}
+	}
+
+	// Fly every shot the player can see across the screen
+	fn animate_shots(&mut self, player : @mut map::Creature) {
+		for player.map.shots.each |shot| {
+			for shot.each |p| {
+				if !player.sees(p) {
+					loop;
+				}
+				let pos = {
+					let rm = map::RelativeMap::new(player.map, &player.pos, player.dir);
+					let offset = player.map.offset(&player.pos, p);
+					rm.untranslate(&(player.pos + offset))
+				};
+				self.draw(player);
+				self.view.draw_sprite(self.screen, self.tiles, &pos, Sprite::shot());
+				self.screen.flip();
+				unsafe {
+					usleep(SHOT_STEP_USEC);
+				}
+			}
+		}
+	}
+
+	// Print messages added to `log` since the last call, the latest one in the caption
+	fn show_messages(&mut self, log : @mut MessageLog) {
+		let new = log.since(self.messages_shown);
+		self.messages_shown = log.total;
+		for new.each |m| {
+			io::println(fmt!("[%u] %s", m.tick, m.text));
+		}
+		if !new.is_empty() {
+			let text = copy new[new.len() - 1].text;
+			sdl::wm::set_caption(fmt!("rustyhex - %s", text), "rustyhex");
 		}
 	}
 
@@ -266,6 +409,13 @@ pub impl UI {
 				self.exit = true;
 				return Some(map::WAIT);
 			},
+			// '>' and '<'
+			event::PeriodKey if strafe => {
+				return Some(map::DESCEND);
+			},
+			event::CommaKey if strafe => {
+				return Some(map::ASCEND);
+			},
 			event::PeriodKey | event::CommaKey => {
 				return Some(map::WAIT);
 			},
@@ -292,28 +442,227 @@ pub impl UI {
 		}
 	}
 
-	fn check_exit_input(&mut self) {
-		match event::poll_event() {
-			event::KeyEvent(key, true , _, _) => {
-				match (key) {
-					event::EscapeKey => {
-						self.exit = true;
-					},
-					_ => {}
+	// View position of the hex under the pixel
+	fn pixel_to_view(&self, px : int, py : int) -> map::Position {
+		let px = px - self.view.x_offset;
+		let py = py - self.view.y_offset;
+		let step = (HEX_WIDTH - HEX_SIDE_WIDTH) as int;
+		let x = (px - HEX_BORDER_WIDTH as int) / step;
+		let y = (py - HEX_BORDER_HEIGHT as int + x * (HEX_HEIGHT as int) / 2) / (HEX_HEIGHT as int);
+
+		// The hex with the nearest center
+		let mut best = map::Position {x: x, y: y};
+		let mut best_dist = int::max_value;
+		for int::range(x - 1, x + 2) |cx| {
+			for int::range(y - 1, y + 2) |cy| {
+				let pos = map::Position {x: cx, y: cy};
+				let dx = pos.to_pix_cx() - px;
+				let dy = pos.to_pix_cy() - py;
+				if dx * dx + dy * dy < best_dist {
+					best = pos;
+					best_dist = dx * dx + dy * dy;
+				}
+			}
+		}
+		best
+	}
+
+	// Map position of a view position, if the player knows the tile there
+	fn travel_target(&self, pos : &map::Position) -> Option<map::Position> {
+		let player = match self.player {
+			Some(p) => p,
+			None => return None
+		};
+		let target = {
+			let rm = map::RelativeMap::new(player.map, &player.pos, player.dir);
+			rm.translate(pos)
+		};
+		if player.knows(&target) {
+			player.map.normalize(&target)
+		} else {
+			None
+		}
+	}
+
+	// Offset to shoot at for a view position, if the player sees the hex there
+	fn fire_target(&self, pos : &map::Position) -> Option<map::Position> {
+		let player = match self.player {
+			Some(p) => p,
+			None => return None
+		};
+		let target = {
+			let rm = map::RelativeMap::new(player.map, &player.pos, player.dir);
+			rm.translate(pos)
+		};
+		if *pos != (map::Position {x: 0, y: 0}) && player.sees(&target) {
+			Some(player.map.offset(&player.pos, &target))
+		} else {
+			None
+		}
+	}
+
+	// Show the name of the selected item in the caption
+	fn show_selected(&mut self, player : @mut map::Creature) {
+		let entries = inventory_entries(player);
+		let text = match self.inventory {
+			Some(i) if i < entries.len() => {
+				let (it, worn) = entries[i];
+				match worn {
+					Some(slot) => fmt!("rustyhex - %s (%s)", it.name, slot.to_str()),
+					None => fmt!("rustyhex - %s (%u/%u)", it.name, i + 1, item::INVENTORY_SIZE)
 				}
 			},
-			_ => {}
+			_ => ~"rustyhex - carrying nothing"
+		};
+		sdl::wm::set_caption(text, "rustyhex");
+	}
+
+	// Handle a key on the inventory screen
+	fn inventory_key(&mut self, key : &event::Key) -> Option<Command> {
+		let player = self.player.get();
+		let selected = self.inventory.get();
+		let entries = inventory_entries(player);
+		let count = entries.len();
+
+		let command = match *key {
+			event::HKey | event::KKey | event::LeftKey | event::UpKey if selected > 0 => {
+				self.inventory = Some(selected - 1);
+				None
+			},
+			event::LKey | event::JKey | event::RightKey | event::DownKey if selected + 1 < count => {
+				self.inventory = Some(selected + 1);
+				None
+			},
+			event::DKey if selected < player.inventory.len() => {
+				self.inventory = None;
+				Some(ACT(map::DROP(selected)))
+			},
+			event::UKey if selected < player.inventory.len() => {
+				self.inventory = None;
+				Some(ACT(map::USE(selected)))
+			},
+			event::EKey if selected < count => {
+				self.inventory = None;
+				match entries[selected] {
+					(_, Some(slot)) => Some(ACT(map::UNEQUIP(slot))),
+					(it, None) if it.slot.is_some() => Some(ACT(map::EQUIP(selected))),
+					_ => None
+				}
+			},
+			event::EscapeKey | event::IKey => {
+				self.inventory = None;
+				None
+			},
+			_ => None
+		};
+		if self.inventory.is_some() {
+			self.show_selected(player);
+		}
+		self.update();
+		command
+	}
+
+	// Handle a key in cursor mode
+	fn cursor_key(&mut self, key : &event::Key) -> Option<Command> {
+		let cursor = self.cursor.get();
+		let dir = match *key {
+			event::KKey | event::UpKey => Some(map::N),
+			event::JKey | event::DownKey => Some(map::S),
+			event::YKey | event::HKey | event::LeftKey => Some(map::NW),
+			event::UKey | event::LKey | event::RightKey => Some(map::NE),
+			event::BKey => Some(map::SW),
+			event::NKey => Some(map::SE),
+			_ => None
+		};
+
+		match (*key, dir) {
+			(_, Some(d)) => {
+				self.cursor = Some(cursor.neighbor(d));
+				self.update();
+				None
+			},
+			(event::ReturnKey, _) if self.aiming => {
+				self.cursor = None;
+				self.update();
+				match self.fire_target(&cursor) {
+					Some(offset) => Some(ACT(map::FIRE(offset))),
+					None => None
+				}
+			},
+			(event::ReturnKey, _) => {
+				self.cursor = None;
+				self.update();
+				match self.travel_target(&cursor) {
+					Some(target) => Some(TRAVEL(target)),
+					None => None
+				}
+			},
+			(event::EscapeKey, _) => {
+				self.cursor = None;
+				self.update();
+				None
+			},
+			_ => None
 		}
 	}
 
-	fn get_input(&mut self) -> map::Action {
+	fn get_command(&mut self) -> Command {
 		loop {
 			match event::wait_event() {
 				event::KeyEvent(key, true , m, _) => {
-					match self.keyevent_to_action(&key, m) {
-						Some(a) => {
-							return a;
+					if self.cursor.is_some() {
+						match self.cursor_key(&key) {
+							Some(c) => return c,
+							None => {}
+						}
+						loop;
+					}
+					if self.inventory.is_some() {
+						match self.inventory_key(&key) {
+							Some(c) => return c,
+							None => {}
+						}
+						loop;
+					}
+					match key {
+						event::TKey | event::FKey => {
+							self.aiming = match key { event::FKey => true, _ => false };
+							self.cursor = Some(map::Position {x: 0, y: 0});
+							self.update();
 						},
+						event::OKey => {
+							return EXPLORE;
+						},
+						event::IKey => {
+							let player = self.player.get();
+							self.inventory = Some(0);
+							self.show_selected(player);
+							self.update();
+						},
+						event::GKey => {
+							return ACT(map::PICKUP);
+						},
+						_ => {
+							match self.keyevent_to_action(&key, m) {
+								Some(a) => {
+									return ACT(a);
+								},
+								None => {}
+							}
+						}
+					}
+				},
+				event::MouseButtonEvent(event::LeftMouse, true, x, y) => {
+					let pos = self.pixel_to_view(x as int, y as int);
+					match self.travel_target(&pos) {
+						Some(target) => return TRAVEL(target),
+						None => {}
+					}
+				},
+				event::MouseButtonEvent(event::RightMouse, true, x, y) => {
+					let pos = self.pixel_to_view(x as int, y as int);
+					match self.fire_target(&pos) {
+						Some(offset) => return ACT(map::FIRE(offset)),
 						None => {}
 					}
 				},
@@ -322,4 +671,59 @@ pub impl UI {
 			}
 		}
 	}
+
+	// Has a key been pressed, e.g. to interrupt travel
+	fn key_pressed(&mut self) -> bool {
+		match event::poll_event() {
+			event::KeyEvent(_, true, _, _) => true,
+			_ => false
+		}
+	}
+}
+
+impl engine::Renderer for UI {
+	fn set_player(&mut self, p : @mut map::Creature) {
+		self.player = Some(p);
+	}
+
+	fn update(&mut self) {
+
+		let player = match self.player {
+			Some(p) => p,
+			None => {
+				return;
+			}
+		};
+
+		self.show_messages(player.map.log);
+
+		match self.inventory {
+			Some(selected) => self.draw_inventory(player, selected),
+			None => self.draw(player)
+		}
+		self.screen.flip();
+		self.animate_shots(player);
+
+		unsafe {
+			usleep(1000);
+		}
+	}
+
+	fn check_exit_input(&mut self) {
+		match event::poll_event() {
+			event::KeyEvent(key, true , _, _) => {
+				match (key) {
+					event::EscapeKey => {
+						self.exit = true;
+					},
+					_ => {}
+				}
+			},
+			_ => {}
+		}
+	}
+
+	fn exit_requested(&self) -> bool {
+		self.exit
+	}
 }